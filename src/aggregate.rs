@@ -0,0 +1,103 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::{AggregateConfig, AggregateFunction, SensorConfig};
+use crate::inverter::SensorValue;
+
+// Keeps the latest samples of aggregated sensors between polling cycles
+#[derive(Default)]
+pub struct Aggregates {
+    samples: HashMap<String, VecDeque<f64>>,
+}
+
+impl Aggregates {
+    // Adds the sample and returns the aggregate of the window,
+    // values that are not numbers are passed through
+    pub fn add(
+        &mut self,
+        sensor: &SensorConfig,
+        aggregate: &AggregateConfig,
+        value: SensorValue,
+    ) -> SensorValue {
+        let sample = match value {
+            SensorValue::Integer(v) => v as f64,
+            SensorValue::Float(v) => v,
+            value => return value,
+        };
+        let samples = self.samples.entry(sensor.name.clone()).or_default();
+        samples.push_back(sample);
+        while samples.len() > aggregate.window {
+            samples.pop_front();
+        }
+        let result = match aggregate.function {
+            AggregateFunction::Min => samples.iter().copied().fold(f64::INFINITY, f64::min),
+            AggregateFunction::Max => samples.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            AggregateFunction::Avg => {
+                return SensorValue::Float(samples.iter().sum::<f64>() / samples.len() as f64);
+            }
+            AggregateFunction::Last => sample,
+        };
+        match value {
+            SensorValue::Integer(_) => SensorValue::Integer(result as i64),
+            _ => SensorValue::Float(result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::SensorConfig;
+    use crate::inverter::SensorValue;
+    use super::Aggregates;
+
+    fn power_sensor(aggregate: &str) -> SensorConfig {
+        serde_yaml::from_str(&format!(r#"
+name: load_power
+value_type: integer
+device_class: "power"
+unit_of_measurement: "W"
+icon: "mdi:flash"
+aggregate: {aggregate}
+"#)).unwrap()
+    }
+
+    #[test]
+    fn test_aggregate_avg() {
+        let sensor = power_sensor("{function: avg, window: 3}");
+        let aggregate = sensor.aggregate.as_ref().unwrap();
+        let mut aggregates = Aggregates::default();
+        let results = [300, 600, 1500, 300]
+            .map(|v| aggregates.add(&sensor, aggregate, SensorValue::Integer(v)));
+        assert_eq!(
+            results,
+            [
+                SensorValue::Float(300.0),
+                SensorValue::Float(450.0),
+                SensorValue::Float(800.0),
+                // The first sample left the window
+                SensorValue::Float(800.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_min_max() {
+        let sensor = power_sensor("{function: max, window: 2}");
+        let aggregate = sensor.aggregate.as_ref().unwrap();
+        let mut aggregates = Aggregates::default();
+        let results = [300, 1500, 600, 400]
+            .map(|v| aggregates.add(&sensor, aggregate, SensorValue::Integer(v)));
+        assert_eq!(results, [300, 1500, 1500, 600].map(SensorValue::Integer));
+
+        let sensor = power_sensor("{function: min, window: 2}");
+        let aggregate = sensor.aggregate.as_ref().unwrap();
+        let mut aggregates = Aggregates::default();
+        let results = [300, 1500, 600, 400]
+            .map(|v| aggregates.add(&sensor, aggregate, SensorValue::Integer(v)));
+        assert_eq!(results, [300, 300, 600, 400].map(SensorValue::Integer));
+        // Missing values do not count as samples
+        assert_eq!(
+            aggregates.add(&sensor, aggregate, SensorValue::Unavailable),
+            SensorValue::Unavailable
+        );
+    }
+}
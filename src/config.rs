@@ -1,31 +1,398 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{DeserializeOwned, Error as _};
+use serde_yaml::Value;
+use snafu::{Whatever, whatever};
+
+use crate::format::{normalize_unit, Unit};
+use crate::hass::{BINARY_SENSOR_DEVICE_CLASSES, SENSOR_DEVICE_CLASSES};
 
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "ConfigFile")]
 pub struct Config {
-    pub inverter: InverterConfig,
+    pub inverters: Vec<InverterConfig>,
     pub mqtt: MqttConfig,
+    // Sensor values are written to InfluxDB as well when set
+    pub influxdb: Option<InfluxDbConfig>,
+}
+
+// Either a single inverter as before or a list of inverters polled by one process
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    inverter: Option<InverterConfig>,
+    #[serde(default)]
+    inverters: Vec<InverterConfig>,
+    mqtt: MqttConfig,
+    influxdb: Option<InfluxDbConfig>,
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = &'static str;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let inverters = match (file.inverter, file.inverters) {
+            (Some(inverter), inverters) if inverters.is_empty() => vec![inverter],
+            (None, inverters) if !inverters.is_empty() => inverters,
+            (Some(_), _) => return Err("only one of inverter and inverters can be set"),
+            (None, _) => return Err("either inverter or inverters must be set"),
+        };
+        Ok(Self { inverters, mqtt: file.mqtt, influxdb: file.influxdb })
+    }
+}
+
+// Lenient loading: invalid sensors of commands are replaced with placeholders to keep
+// positions of the other fields, invalid commands and derived sensors are removed.
+// Returns descriptions of the skipped entries.
+pub fn drop_invalid_entries(inverter: &mut Value) -> Vec<String> {
+    let mut skipped = vec!();
+    if let Some(commands) = inverter.get_mut("commands") {
+        skipped.extend(drop_invalid_commands(commands));
+    }
+    if let Some(Value::Sequence(derived)) = inverter.get_mut("derived_sensors") {
+        retain_valid::<DerivedSensorConfig>(derived, "derived sensor", "name", &mut skipped);
+    }
+    skipped
+}
+
+pub fn drop_invalid_commands(commands: &mut Value) -> Vec<String> {
+    let mut skipped = vec!();
+    let Value::Sequence(commands) = commands else {
+        return skipped;
+    };
+    for command in commands.iter_mut() {
+        let Some(Value::Sequence(sensors)) = command.get_mut("sensors") else {
+            continue;
+        };
+        for sensor in sensors.iter_mut().filter(|s| !is_ignored_field(s)) {
+            if let Err(e) = serde_yaml::from_value::<SensorConfig>(sensor.clone()) {
+                skipped.push(format!("Skipping sensor '{}': {e}", entry_name(sensor, "name")));
+                *sensor = Value::Null;
+            }
+        }
+    }
+    retain_valid::<CommandConfig>(commands, "command", "command", &mut skipped);
+    skipped
+}
+
+fn retain_valid<T: DeserializeOwned>(
+    entries: &mut Vec<Value>,
+    kind: &str,
+    name_key: &str,
+    skipped: &mut Vec<String>,
+) {
+    entries.retain(|entry| match serde_yaml::from_value::<T>(entry.clone()) {
+        Ok(_) => true,
+        Err(e) => {
+            skipped.push(format!("Skipping {kind} '{}': {e}", entry_name(entry, name_key)));
+            false
+        }
+    });
+}
+
+fn entry_name<'a>(entry: &'a Value, name_key: &str) -> &'a str {
+    entry.get(name_key).and_then(Value::as_str).unwrap_or("<unnamed>")
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct InverterConfig {
     pub id: String,
     pub name: String,
     pub manufacturer: String,
     pub model: String,
-    pub usb: UsbConfig,
+    #[serde(flatten)]
+    pub device: DeviceConfig,
+    pub address: Option<String>,
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: usize,
+    #[serde(default = "default_unavailable_after_failures")]
+    pub unavailable_after_failures: u32,
+    #[serde(default = "default_log_throttle_secs")]
+    pub log_throttle_secs: u64,
+    #[serde(default = "default_query_interval_secs")]
+    pub query_interval_secs: u64,
+    #[serde(default)]
+    pub decimal_comma: bool,
+    #[serde(default = "default_pad_byte")]
+    pub pad_byte: u8,
+    // Frames are padded to a multiple of it rather than cut, so any length fits every command.
+    // Defaults to the report size for USB devices, other devices get unpadded frames
+    pub pad_length: Option<usize>,
+    #[serde(default)]
+    pub framing: FramingConfig,
+    // Device is released between polling cycles for other tools
+    #[serde(default)]
+    pub reopen_each_cycle: bool,
+    // No command is sent within this window after the previous one
+    #[serde(default)]
+    pub min_command_interval_ms: u64,
+    // Discovery is re-sent periodically to restore lost retained messages
+    pub rediscovery_interval_secs: Option<u64>,
+    // Mirrors response frames as hex for protocol debugging
+    #[serde(default)]
+    pub publish_raw_hex: bool,
+    // Non-standard CRC-16 of the inverter variant, XMODEM by default
+    pub crc: Option<CrcConfig>,
+    // Consecutive CRC failures hinting at a wrong baud rate or protocol variant
+    #[serde(default = "default_crc_mismatch_threshold")]
+    pub crc_mismatch_threshold: u32,
+    // Shortcut for well-known algorithms instead of the crc parameters
+    pub crc_algorithm: Option<CrcAlgorithm>,
+    // Latest values of all sensors for tools not subscribed to MQTT
+    pub snapshot_file: Option<PathBuf>,
+    pub post_process_command: Option<String>,
+    // Hanging commands are killed, the raw values are published instead
+    #[serde(default = "default_post_process_timeout_ms")]
+    pub post_process_timeout_ms: u64,
+    // Waiting for the device, the probe and failed polling cycles give up after this many
+    // retries, so a supervisor can restart the process. Retried forever by default
+    pub max_retries: Option<u32>,
+    // Startup fails when the USB device is not found in time, searched forever by default
+    pub device_search_timeout_secs: Option<u64>,
+    // Discovery is published only after the inverter answers this command, e.g. QPI
+    pub probe_command: Option<String>,
+    pub commands_file: Option<PathBuf>,
+    #[serde(default)]
     pub commands: Vec<CommandConfig>,
+    #[serde(default)]
+    pub derived_sensors: Vec<DerivedSensorConfig>,
+    #[serde(default)]
+    pub sensor_groups: Vec<SensorGroupConfig>,
+    // Parts of a complex unit, like the charger or the BMS, sensors can be assigned to
+    #[serde(default)]
+    pub sub_devices: Vec<SubDeviceConfig>,
+    // Settings Home Assistant can change, published as select entities
+    #[serde(default)]
+    pub settings: Vec<SettingConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+impl InverterConfig {
+    // Sensors of all commands followed by derived sensors
+    pub fn sensors(&self) -> impl Iterator<Item = &SensorConfig> {
+        self.commands.iter()
+            .flat_map(|c| c.sensors.iter().filter_map(|s| s.as_ref()))
+            .chain(self.derived_sensors.iter().map(|d| &d.sensor))
+    }
+
+    pub fn capture_extra_sensors(&self) -> impl Iterator<Item = &str> {
+        self.commands.iter().filter_map(|c| c.capture_extra.as_deref())
+    }
+
+    // Status bits together with the sensors they are expanded from
+    pub fn status_bits(&self) -> impl Iterator<Item = (&SensorConfig, &BitConfig)> {
+        self.sensors().flat_map(|s| s.bits.iter().flatten().map(move |b| (s, b)))
+    }
+
+    pub fn sub_device(&self, id: &str) -> Option<&SubDeviceConfig> {
+        self.sub_devices.iter().find(|d| d.id == id)
+    }
+
+    pub fn sensor(&self, name: &str) -> Option<&SensorConfig> {
+        self.sensors().find(|s| s.name == name)
+    }
+
+    // Grouped sensors are only published as attributes of their group entity
+    pub fn is_grouped(&self, sensor_name: &str) -> bool {
+        self.sensor_groups.iter().any(|g| g.sensors.iter().any(|name| name == sensor_name))
+    }
+
+    pub fn pad_length(&self) -> usize {
+        self.pad_length.unwrap_or(match self.device {
+            DeviceConfig::Usb(_) => crate::inverter::DEFAULT_PAD_LENGTH,
+            DeviceConfig::Raw(_) | DeviceConfig::Serial(_) | DeviceConfig::Tcp(_) => 0,
+        })
+    }
+
+    // Sensors are available depending on values of other sensors, status bits
+    // or captured extra fields
+    pub fn validate_availability(&self) -> Result<(), Whatever> {
+        for sensor in self.sensors() {
+            let Some(condition) = &sensor.available_when else {
+                continue;
+            };
+            if condition.sensor == sensor.name {
+                whatever!("'{}' sensor availability depends on itself", sensor.name);
+            }
+            let is_known = self.sensor(&condition.sensor).is_some()
+                || self.status_bits().any(|(_, bit)| bit.name == condition.sensor)
+                || self.capture_extra_sensors().any(|name| name == condition.sensor);
+            if !is_known {
+                whatever!(
+                    "'{}' sensor availability depends on unknown '{}' sensor",
+                    sensor.name, condition.sensor
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Frames are sent to USB devices in whole reports, a shorter last report is not accepted
+    pub fn validate_pad_length(&self) -> Result<(), Whatever> {
+        let pad_length = self.pad_length();
+        if let DeviceConfig::Usb(_) = self.device {
+            if pad_length == 0 || !pad_length.is_multiple_of(crate::inverter::REPORT_SIZE) {
+                whatever!(
+                    "Pad length {pad_length} of a usb device must be a multiple of {} bytes",
+                    crate::inverter::REPORT_SIZE
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_max_response_bytes() -> usize {
+    crate::inverter::DEFAULT_MAX_RESPONSE_BYTES
+}
+
+fn default_pad_byte() -> u8 {
+    crate::inverter::DEFAULT_PAD_BYTE
+}
+
+fn default_unavailable_after_failures() -> u32 {
+    3
+}
+
+fn default_log_throttle_secs() -> u64 {
+    300
+}
+
+fn default_post_process_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_crc_mismatch_threshold() -> u32 {
+    5
+}
+
+fn default_query_interval_secs() -> u64 {
+    30
+}
+
+// CRC-16 algorithms used by inverter clones
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CrcAlgorithm {
+    #[serde(rename = "xmodem")]
+    Xmodem,
+    #[serde(rename = "ccitt_false")]
+    CcittFalse,
+    #[serde(rename = "modbus")]
+    Modbus,
+}
+
+// Parameters of a CRC-16 algorithm as in the CRC catalogue
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct CrcConfig {
+    pub poly: u16,
+    #[serde(default)]
+    pub init: u16,
+    #[serde(default)]
+    pub refin: bool,
+    #[serde(default)]
+    pub refout: bool,
+    #[serde(default)]
+    pub xorout: u16,
+}
+
+// How the end of a response frame is found
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum FramingConfig {
+    #[default]
+    #[serde(rename = "end_marker")]
+    EndMarker,
+    #[serde(rename = "length_prefixed")]
+    LengthPrefixed(LengthPrefixConfig),
+}
+
+// Prefix holds the number of frame bytes following it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LengthPrefixConfig {
+    #[serde(default = "default_prefix_size")]
+    pub prefix_size: usize,
+    #[serde(default)]
+    pub endian: Endian,
+}
+
+fn default_prefix_size() -> usize {
+    2
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum Endian {
+    #[default]
+    #[serde(rename = "big")]
+    Big,
+    #[serde(rename = "little")]
+    Little,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub enum DeviceConfig {
+    #[serde(rename = "usb")]
+    Usb(UsbConfig),
+    #[serde(rename = "raw")]
+    Raw(RawConfig),
+    #[serde(rename = "serial")]
+    Serial(SerialConfig),
+    #[serde(rename = "tcp")]
+    Tcp(TcpConfig),
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct UsbConfig {
     pub vendor_id: u16,
     pub product_id: u16,
+    // Stable udev symlink selecting one of several devices with the same ids
+    pub device_path: Option<PathBuf>,
     pub interface: u8,
     pub request_params: RequestParams,
     pub response_params: ResponseParams,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RawConfig {
+    pub path: PathBuf,
+    pub timeout_ms: u32,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SerialConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    pub timeout_ms: u32,
+    // Tried in turn after repeated CRC failures to detect the baud rate of the inverter
+    #[serde(default)]
+    pub candidate_baud_rates: Vec<u32>,
+    // Modem control lines, left as the driver set them when not given
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    // host:port of a serial-to-Ethernet adapter
+    pub address: String,
+    pub timeout_ms: u32,
+}
+
+// Voltronic compatible inverters talk at 2400 baud
+fn default_baud_rate() -> u32 {
+    2400
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct RequestParams {
     pub request_type: u8,
     pub request: u8,
@@ -34,26 +401,415 @@ pub struct RequestParams {
     pub timeout_ms: u32,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ResponseParams {
     pub endpoint: u8,
     pub timeout_ms: u32,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct CommandConfig {
     pub command: String,
+    pub argument: Option<String>,
+    pub address: Option<String>,
+    #[serde(default)]
+    pub skip_leading_fields: usize,
+    // Polling interval of slowly changing data, query_interval_secs by default
+    pub interval_secs: Option<u64>,
+    // Status suffix after the data fields, e.g. a mode letter
+    #[serde(default)]
+    pub trailing_fields_to_drop: usize,
+    #[serde(default)]
+    pub parser: ParserConfig,
+    #[serde(default)]
+    pub encoding: ResponseEncoding,
+    #[serde(default)]
+    pub on_short_response: ShortResponsePolicy,
+    #[serde(deserialize_with = "deserialize_sensors")]
     pub sensors: Vec<Option<SensorConfig>>,
+    // Name of a string sensor holding the fields left after all sensors
+    pub capture_extra: Option<String>,
+    // Splits the response into sections parsed separately,
+    // every section fills the given number of sensors
+    pub section_separator: Option<String>,
+    #[serde(default)]
+    pub section_sizes: Vec<usize>,
+}
+
+// Fields without a sensor are marked with `null` or `{ignore: true}`
+fn deserialize_sensors<'de, D>(deserializer: D) -> Result<Vec<Option<SensorConfig>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|value| {
+            if is_ignored_field(&value) {
+                return Ok(None);
+            }
+            let name = entry_name(&value, "name").to_string();
+            serde_yaml::from_value(value)
+                .map(Some)
+                .map_err(|e| D::Error::custom(format!("'{name}' sensor: {e}")))
+        })
+        .collect()
+}
+
+fn is_ignored_field(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Mapping(mapping) => {
+            mapping.len() == 1 && mapping.get("ignore") == Some(&Value::Bool(true))
+        }
+        _ => false,
+    }
+}
+
+// What to do when a response has fewer fields than sensors
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ShortResponsePolicy {
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "unavailable")]
+    Unavailable,
+}
+
+impl CommandConfig {
+    pub fn full_command(&self) -> String {
+        match &self.argument {
+            Some(arg) => format!("{}{arg}", self.command),
+            None => self.command.clone(),
+        }
+    }
+
+    // Command without sensors is only sent for its side effects or to check the connection
+    pub fn is_ping(&self) -> bool {
+        self.capture_extra.is_none() && self.sensors.iter().all(Option::is_none)
+    }
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct SensorConfig {
     pub name: String,
     pub human_name: Option<String>,
+    pub object_id: Option<String>,
+    pub unique_id: Option<String>,
     pub value_type: ValueType,
     pub device_class: String,
     pub unit_of_measurement: String,
     pub icon: String,
+    pub state_class: Option<StateClass>,
+    pub last_reset_value_template: Option<String>,
+    pub value_template: Option<String>,
+    pub available_when: Option<Condition>,
+    pub binary_field: Option<BinaryField>,
+    // Radix of integer fields without a prefix, e.g. 16 for "1A"
+    pub radix: Option<u32>,
+    // Published value is raw * scale + offset
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    // Tokens of boolean fields, "1" and "0" by default
+    pub true_value: Option<String>,
+    pub false_value: Option<String>,
+    // strftime-like format of datetime fields, e.g. "%Y%m%d%H%M%S"
+    pub datetime_format: Option<String>,
+    // Offset of datetime fields without one, e.g. "+02:00", UTC by default
+    pub utc_offset: Option<String>,
+    // String values are preserved as is unless these are set
+    #[serde(default)]
+    pub trim: bool,
+    #[serde(default)]
+    pub strip_leading_zeros: bool,
+    // Additional topics the state is mirrored to
+    #[serde(default)]
+    pub extra_state_topics: Vec<String>,
+    // Sensor of another command whose value is used when this one is missing
+    pub fallback: Option<String>,
+    // Sub device the entity belongs to, the inverter itself by default
+    pub device: Option<String>,
+    // Binary sensors for the characters of a status flags field, null skips a position
+    #[serde(default)]
+    pub bits: Vec<Option<BitConfig>>,
+    // Published value is aggregated over the latest samples, e.g. to smooth spikes
+    pub aggregate: Option<AggregateConfig>,
+    // Names of the raw codes of enum fields, e.g. "0" -> "Off"
+    #[serde(default)]
+    pub map: HashMap<String, String>,
+}
+
+impl SensorConfig {
+    // Home Assistant ignores entities with unknown device classes and warns about units
+    // it cannot convert, so such sensors are rejected at startup
+    pub fn validate(&self) -> Result<(), Whatever> {
+        let mut problems = vec!();
+        let is_binary = matches!(self.value_type, ValueType::Boolean);
+        let device_classes = if is_binary {
+            BINARY_SENSOR_DEVICE_CLASSES
+        } else {
+            SENSOR_DEVICE_CLASSES
+        };
+        let device_class = self.device_class.as_str();
+        if !device_class.is_empty() && !device_classes.contains(&device_class) {
+            problems.push(format!("unknown device class '{device_class}'"));
+        } else if !is_binary {
+            if let Unit::Unknown = normalize_unit(device_class, &self.unit_of_measurement) {
+                problems.push(format!(
+                    "unit '{}' does not match '{device_class}' device class",
+                    self.unit_of_measurement
+                ));
+            }
+        }
+        for bit in self.bits.iter().flatten() {
+            let device_class = bit.device_class.as_str();
+            if !device_class.is_empty() && !BINARY_SENSOR_DEVICE_CLASSES.contains(&device_class) {
+                problems.push(
+                    format!("unknown device class '{device_class}' of '{}' bit", bit.name)
+                );
+            }
+        }
+        if !problems.is_empty() {
+            whatever!("'{}' sensor: {}", self.name, problems.join(", "));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateConfig {
+    pub function: AggregateFunction,
+    // Number of the latest samples
+    pub window: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    #[serde(rename = "min")]
+    Min,
+    #[serde(rename = "max")]
+    Max,
+    #[serde(rename = "avg")]
+    Avg,
+    #[serde(rename = "last")]
+    Last,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BitConfig {
+    pub name: String,
+    pub human_name: Option<String>,
+    #[serde(default)]
+    pub device_class: String,
+    #[serde(default)]
+    pub icon: String,
+}
+
+// Published as a separate Home Assistant device linked to the inverter.
+// Manufacturer and model are inherited from the inverter when omitted
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SubDeviceConfig {
+    pub id: String,
+    pub name: String,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+// Setting written with a command made of the prefix and the argument of the selected option,
+// e.g. POP02 for the output source priority. The inverter replies with ACK or NAK
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SettingConfig {
+    pub name: String,
+    pub human_name: Option<String>,
+    pub command: String,
+    pub options: Vec<SettingOption>,
+    #[serde(default)]
+    pub icon: String,
+    // Sub device the entity belongs to, the inverter itself by default
+    pub device: Option<String>,
+    // Some firmware acknowledges out-of-range values but ignores them,
+    // so the setting is read back from the sensor after a write
+    #[serde(default)]
+    pub verify_after_write: bool,
+    pub read_back: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SettingOption {
+    // Shown in Home Assistant
+    pub name: String,
+    pub argument: String,
+    // Value of the read back sensor, the argument by default
+    pub value: Option<String>,
+}
+
+impl SettingConfig {
+    pub fn option(&self, name: &str) -> Option<&SettingOption> {
+        self.options.iter().find(|o| o.name == name)
+    }
+
+    // Option matching the value of the read back sensor
+    pub fn option_by_value(&self, value: &str) -> Option<&SettingOption> {
+        self.options.iter().find(|o| o.value.as_deref().unwrap_or(&o.argument) == value)
+    }
+}
+
+// Single entity for related sensors, e.g. voltages of all phases. The first sensor provides
+// the state and discovery metadata, values of all sensors are published as attributes
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SensorGroupConfig {
+    pub name: String,
+    pub human_name: Option<String>,
+    pub sensors: Vec<String>,
+}
+
+// Sensor computed from the latest values of other sensors
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DerivedSensorConfig {
+    #[serde(flatten)]
+    pub sensor: SensorConfig,
+    #[serde(flatten)]
+    pub rule: DerivedRule,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum DerivedRule {
+    #[serde(rename = "signed")]
+    Signed(SignedRule),
+    #[serde(rename = "rate")]
+    Rate(RateRule),
+}
+
+// Negates the magnitude when the direction condition is met
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SignedRule {
+    pub magnitude: String,
+    pub negative_when: Condition,
+}
+
+// Change of a counter per second between two polling cycles multiplied by the scale,
+// e.g. 3600000 turns kWh into W
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateRule {
+    pub source: String,
+    #[serde(default = "default_rate_scale")]
+    pub scale: f64,
+}
+
+fn default_rate_scale() -> f64 {
+    1.0
+}
+
+// Decoder of the response data selected by the type key
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ParserConfig {
+    #[default]
+    #[serde(rename = "whitespace")]
+    Whitespace,
+    #[serde(rename = "fixed_width")]
+    FixedWidth(FixedWidthConfig),
+    #[serde(rename = "binary")]
+    Binary,
+    // Fields of a data frame are named by a header frame sent before it, sensors are matched
+    // by name so reordered fields of other firmware versions are still parsed
+    #[serde(rename = "header")]
+    Header,
+}
+
+// Character encoding of the response, binary responses are not decoded as text
+// and need the binary parser
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ResponseEncoding {
+    #[default]
+    #[serde(rename = "utf8")]
+    Utf8,
+    #[serde(rename = "latin1")]
+    Latin1,
+    #[serde(rename = "binary")]
+    Binary,
+}
+
+// Widths of consecutive fields in the order of sensors
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixedWidthConfig {
+    pub widths: Vec<usize>,
+}
+
+// Location of a sensor value inside a binary response
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BinaryField {
+    pub offset: usize,
+    pub width: usize,
+    #[serde(default)]
+    pub decoder: FieldDecoder,
+    // Only used by the integer decoder
+    #[serde(default)]
+    pub endian: Endian,
+    #[serde(default)]
+    pub signed: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum FieldDecoder {
+    #[default]
+    #[serde(rename = "integer")]
+    Integer,
+    #[serde(rename = "bcd")]
+    Bcd,
+}
+
+// Condition on the latest published value of another sensor
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub sensor: String,
+    #[serde(flatten)]
+    pub op: ConditionOp,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum ConditionOp {
+    #[serde(rename = "equals")]
+    Equals(String),
+    #[serde(rename = "not_equals")]
+    NotEquals(String),
+}
+
+impl Condition {
+    // Unknown sensor values never satisfy a condition
+    pub fn is_met(&self, values: &HashMap<String, String>) -> bool {
+        match (values.get(&self.sensor), &self.op) {
+            (Some(value), ConditionOp::Equals(expected)) => value == expected,
+            (Some(value), ConditionOp::NotEquals(expected)) => value != expected,
+            (None, _) => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum StateClass {
+    #[serde(rename = "measurement")]
+    Measurement,
+    #[serde(rename = "total")]
+    Total,
+    #[serde(rename = "total_increasing")]
+    TotalIncreasing,
 }
 
 #[derive(Deserialize, Debug)]
@@ -64,16 +820,652 @@ pub enum ValueType {
     Float,
     #[serde(rename = "string")]
     String,
+    #[serde(rename = "boolean")]
+    Boolean,
+    #[serde(rename = "datetime")]
+    DateTime,
+    // Raw codes are translated with the map of the sensor
+    #[serde(rename = "enum")]
+    Enum,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct MqttConfig {
     pub address: String,
     pub auth: Option<MqttAuth>,
+    pub tls: Option<MqttTls>,
+    #[serde(default)]
+    pub discovery_format: DiscoveryFormat,
+    // Prepended to every published topic to share a broker with other systems
+    #[serde(default)]
+    pub topic_prefix: String,
+    // QoS of discovery messages of component types without own options
+    #[serde(default)]
+    pub discovery_qos: i32,
+    // Overrides how discovery messages of a component type are published
+    #[serde(default)]
+    pub discovery_publish: HashMap<Component, PublishOptions>,
+    // Retained states are seen by clients connecting between polls
+    #[serde(default)]
+    pub state_qos: i32,
+    #[serde(default)]
+    pub state_retain: bool,
+    // Derived from the inverter id when not set, so the connection is identifiable
+    pub client_id: Option<String>,
+    // Connecting and publishing discovery give up after this many retries, forever by default
+    pub max_retries: Option<u32>,
+}
+
+impl MqttConfig {
+    pub fn discovery_options(&self, component: Component) -> PublishOptions {
+        self.discovery_publish.get(&component).copied()
+            .unwrap_or(PublishOptions { qos: self.discovery_qos, retain: default_retain() })
+    }
+
+    pub fn client_id(&self, inverter_id: &str) -> String {
+        self.client_id.clone().unwrap_or_else(|| format!("inverter2mqtt_{inverter_id}"))
+    }
+}
+
+// Home Assistant entity types, used as a part of the discovery topic
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Component {
+    #[serde(rename = "sensor")]
+    Sensor,
+    #[serde(rename = "binary_sensor")]
+    BinarySensor,
+    #[serde(rename = "number")]
+    Number,
+    #[serde(rename = "select")]
+    Select,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Component::Sensor => "sensor",
+            Component::BinarySensor => "binary_sensor",
+            Component::Number => "number",
+            Component::Select => "select",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PublishOptions {
+    #[serde(default)]
+    pub qos: i32,
+    #[serde(default = "default_retain")]
+    pub retain: bool,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        Self { qos: 0, retain: default_retain() }
+    }
+}
+
+fn default_retain() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum DiscoveryFormat {
+    #[default]
+    #[serde(rename = "verbose")]
+    Verbose,
+    #[serde(rename = "abbreviated")]
+    Abbreviated,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct MqttAuth {
     pub user: String,
     pub password: String,
 }
+
+// Paths to PEM files, client certificate and key enable mutual TLS
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MqttTls {
+    #[serde(alias = "ca_cert")]
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    // Accepts any server certificate, only for testing against self-signed brokers
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+// InfluxDB v2 endpoint, e.g. http://localhost:8086
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "influxdb"), allow(dead_code))]
+pub struct InfluxDbConfig {
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    // Lines kept while the server is unreachable, the oldest ones are dropped
+    #[serde(default = "default_influxdb_max_buffered_lines")]
+    pub max_buffered_lines: usize,
+    #[serde(default = "default_influxdb_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_influxdb_measurement() -> String {
+    "inverter".to_string()
+}
+
+fn default_influxdb_max_buffered_lines() -> usize {
+    1000
+}
+
+fn default_influxdb_timeout_ms() -> u64 {
+    5000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        drop_invalid_entries,
+        CommandConfig,
+        Component,
+        Condition,
+        Config,
+        CrcConfig,
+        DeviceConfig,
+        FixedWidthConfig,
+        InfluxDbConfig,
+        InverterConfig,
+        MqttConfig,
+        MqttTls,
+        ParserConfig,
+        PublishOptions,
+        SensorConfig,
+        SerialConfig,
+        SettingConfig,
+        StateClass,
+        ValueType,
+    };
+
+    #[test]
+    fn test_parse_example_config() {
+        let config: Config = serde_yaml::from_str(include_str!("../powmr.yaml")).unwrap();
+        let DeviceConfig::Usb(usb) = &config.inverters[0].device else {
+            panic!("Expected usb device config");
+        };
+        assert_eq!((usb.vendor_id, usb.product_id), (0x0665, 0x5161));
+        assert_eq!(config.inverters[0].commands[0].command, "QPIGS");
+        assert_eq!(config.inverters[0].commands[0].sensors.len(), 15);
+        assert!(
+            config.inverters[0].sensors().all(|s| s.state_class == Some(StateClass::Measurement))
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_inverters() {
+        let config: Config = serde_yaml::from_str(r#"
+inverters:
+- id: "first"
+  name: "First Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  raw: {path: "/dev/hidraw0", timeout_ms: 500}
+  commands: []
+- id: "second"
+  name: "Second Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  raw: {path: "/dev/hidraw1", timeout_ms: 500}
+  commands: []
+mqtt:
+  address: "localhost:1883"
+"#).unwrap();
+        let ids = config.inverters.iter().map(|i| i.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["first", "second"]);
+
+        let err = serde_yaml::from_str::<Config>(r#"
+inverters: []
+mqtt:
+  address: "localhost:1883"
+"#).unwrap_err();
+        assert_eq!(err.to_string(), "either inverter or inverters must be set");
+    }
+
+    #[test]
+    fn test_parse_unknown_fields() {
+        let err = serde_yaml::from_str::<Config>(r#"
+inverter:
+  id: "test"
+  name: "Test Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  raw: {path: "/dev/hidraw0", timeout_ms: 500}
+  commands:
+  - command: "QPIGS"
+    sensors:
+    - {name: voltage, value_type: float, unit_of_measure: "V"}
+mqtt:
+  address: "localhost:1883"
+"#).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "inverter.commands[0]: 'voltage' sensor: unknown field `unit_of_measure`"
+            ),
+            "{err}"
+        );
+
+        let err = serde_yaml::from_str::<Config>(r#"
+inverter:
+  id: "test"
+  name: "Test Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  serial: {path: "/dev/ttyUSB0", timeout_ms: 500, dtr: false, rtss: false}
+  commands: []
+mqtt:
+  address: "localhost:1883"
+"#).unwrap_err();
+        assert!(err.to_string().starts_with("inverter: unknown field `rtss`"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_raw_device_config() {
+        let config: Config = serde_yaml::from_str(r#"
+inverter:
+  id: "test"
+  name: "Test Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  raw:
+    path: "/dev/ttyUSB0"
+    timeout_ms: 500
+  commands: []
+mqtt:
+  address: "localhost:1883"
+"#).unwrap();
+        let DeviceConfig::Raw(raw) = &config.inverters[0].device else {
+            panic!("Expected raw device config");
+        };
+        assert_eq!(raw.path.to_str(), Some("/dev/ttyUSB0"));
+        assert_eq!(raw.timeout_ms, 500);
+        assert_eq!(config.inverters[0].query_interval_secs, 30);
+    }
+
+    #[test]
+    fn test_parse_serial_device_config() {
+        let config: Config = serde_yaml::from_str(r#"
+inverter:
+  id: "test"
+  name: "Test Inverter"
+  manufacturer: "Test"
+  model: "Test"
+  serial:
+    path: "/dev/ttyUSB0"
+    timeout_ms: 1000
+  commands: []
+mqtt:
+  address: "localhost:1883"
+"#).unwrap();
+        let DeviceConfig::Serial(serial) = &config.inverters[0].device else {
+            panic!("Expected serial device config");
+        };
+        assert_eq!(serial.path.to_str(), Some("/dev/ttyUSB0"));
+        assert_eq!(serial.baud_rate, 2400);
+        assert_eq!(serial.timeout_ms, 1000);
+        assert_eq!(serial.dtr, None);
+        assert_eq!(serial.rts, None);
+
+        let serial: SerialConfig = serde_yaml::from_str(r#"
+path: "/dev/ttyUSB0"
+timeout_ms: 1000
+dtr: true
+rts: false
+"#).unwrap();
+        assert_eq!(serial.dtr, Some(true));
+        assert_eq!(serial.rts, Some(false));
+    }
+
+    #[test]
+    fn test_parse_pad_length() {
+        let inverter = |device: &str| serde_yaml::from_str::<InverterConfig>(&format!(r#"
+id: "test"
+name: "Test Inverter"
+manufacturer: "Test"
+model: "Test"
+{device}
+"#)).unwrap();
+        let usb = r#"usb:
+  vendor_id: 0x0665
+  product_id: 0x5161
+  interface: 0
+  request_params: {request_type: 0x21, request: 0x09, value: 0x200, index: 0, timeout_ms: 100}
+  response_params: {endpoint: 0x81, timeout_ms: 100}"#;
+        assert_eq!(inverter(usb).pad_length(), 8);
+        assert_eq!(inverter(r#"serial: {path: "/dev/ttyUSB0", timeout_ms: 1000}"#).pad_length(), 0);
+        assert_eq!(inverter(r#"tcp: {address: "localhost:1", timeout_ms: 1000}"#).pad_length(), 0);
+        assert_eq!(inverter(r#"raw: {path: "/dev/ttyUSB0", timeout_ms: 500}"#).pad_length(), 0);
+        assert_eq!(
+            inverter("raw: {path: \"/dev/hidraw0\", timeout_ms: 500}\npad_length: 8").pad_length(),
+            8
+        );
+
+        assert!(inverter(usb).validate_pad_length().is_ok());
+        assert!(inverter(&format!("{usb}\npad_length: 16")).validate_pad_length().is_ok());
+        assert_eq!(
+            inverter(&format!("{usb}\npad_length: 12")).validate_pad_length()
+                .unwrap_err().to_string(),
+            "Pad length 12 of a usb device must be a multiple of 8 bytes"
+        );
+        assert!(inverter(&format!("{usb}\npad_length: 0")).validate_pad_length().is_err());
+        assert!(inverter("raw: {path: \"/dev/ttyUSB0\", timeout_ms: 500}\npad_length: 5")
+            .validate_pad_length().is_ok());
+    }
+
+    #[test]
+    fn test_parse_sensor_groups() {
+        let inverter: InverterConfig = serde_yaml::from_str(r#"
+id: "test"
+name: "Test Inverter"
+manufacturer: "Test"
+model: "Test"
+raw:
+  path: "/dev/ttyUSB0"
+  timeout_ms: 500
+sensor_groups:
+- name: grid_voltage
+  sensors: [grid_voltage_l1, grid_voltage_l2, grid_voltage_l3]
+"#).unwrap();
+        assert_eq!(inverter.sensor_groups[0].name, "grid_voltage");
+        assert!(inverter.is_grouped("grid_voltage_l2"));
+        assert!(!inverter.is_grouped("grid_voltage"));
+    }
+
+    #[test]
+    fn test_parse_sub_devices() {
+        let inverter: InverterConfig = serde_yaml::from_str(r#"
+id: "test"
+name: "Test Inverter"
+manufacturer: "Test"
+model: "Test"
+raw:
+  path: "/dev/ttyUSB0"
+  timeout_ms: 500
+sub_devices:
+- id: bms
+  name: "Test Battery"
+  model: "Test BMS"
+commands:
+- command: QPIGS
+  sensors:
+  - name: battery_voltage
+    value_type: float
+    device_class: "voltage"
+    unit_of_measurement: "V"
+    icon: "mdi:battery"
+    device: bms
+"#).unwrap();
+        let bms = inverter.sub_device("bms").unwrap();
+        assert_eq!(bms.model.as_deref(), Some("Test BMS"));
+        assert_eq!(bms.manufacturer, None);
+        assert_eq!(inverter.sensor("battery_voltage").unwrap().device.as_deref(), Some("bms"));
+        assert!(inverter.sub_device("mppt").is_none());
+    }
+
+    #[test]
+    fn test_validate_availability() {
+        let inverter = |condition_sensor: &str| serde_yaml::from_str::<InverterConfig>(&format!(r#"
+id: "test"
+name: "Test Inverter"
+manufacturer: "Test"
+model: "Test"
+raw:
+  path: "/dev/ttyUSB0"
+  timeout_ms: 500
+commands:
+- command: QPIGS
+  sensors:
+  - name: mode
+    value_type: string
+    device_class: ""
+    unit_of_measurement: ""
+    icon: "mdi:state-machine"
+  - name: grid_voltage
+    value_type: float
+    device_class: "voltage"
+    unit_of_measurement: "V"
+    icon: "mdi:flash"
+    available_when: {{sensor: {condition_sensor}, equals: "L"}}
+"#)).unwrap();
+        assert!(inverter("mode").validate_availability().is_ok());
+        assert_eq!(
+            inverter("mod").validate_availability().unwrap_err().to_string(),
+            "'grid_voltage' sensor availability depends on unknown 'mod' sensor"
+        );
+        assert_eq!(
+            inverter("grid_voltage").validate_availability().unwrap_err().to_string(),
+            "'grid_voltage' sensor availability depends on itself"
+        );
+    }
+
+    #[test]
+    fn test_parse_setting() {
+        let setting: SettingConfig = serde_yaml::from_str(r#"
+name: output_source_priority
+command: POP
+options:
+- {name: "Utility", argument: "00"}
+- {name: "SBU", argument: "02", value: "2"}
+"#).unwrap();
+        assert_eq!(setting.option("SBU").unwrap().argument, "02");
+        assert!(setting.option("Solar").is_none());
+        assert!(setting.device.is_none());
+        assert!(!setting.verify_after_write);
+        assert_eq!(setting.option_by_value("00").unwrap().name, "Utility");
+        assert_eq!(setting.option_by_value("2").unwrap().name, "SBU");
+        assert!(setting.option_by_value("02").is_none());
+    }
+
+    #[test]
+    fn test_condition() {
+        let condition: Condition = serde_yaml::from_str("{sensor: mode, equals: L}").unwrap();
+        let mut values = HashMap::new();
+        assert!(!condition.is_met(&values));
+        values.insert("mode".to_string(), "L".to_string());
+        assert!(condition.is_met(&values));
+        values.insert("mode".to_string(), "B".to_string());
+        assert!(!condition.is_met(&values));
+
+        let condition: Condition = serde_yaml::from_str("{sensor: mode, not_equals: L}").unwrap();
+        assert!(condition.is_met(&values));
+    }
+
+    #[test]
+    fn test_mqtt_client_id() {
+        let mut mqtt: MqttConfig = serde_yaml::from_str(r#"address: "localhost:1883""#).unwrap();
+        assert_eq!(mqtt.client_id("powmr"), "inverter2mqtt_powmr");
+        mqtt.client_id = Some("garage-inverter".to_string());
+        assert_eq!(mqtt.client_id("powmr"), "garage-inverter");
+    }
+
+    #[test]
+    fn test_validate_sensor() {
+        let sensor = |device_class: &str, unit: &str| -> SensorConfig {
+            serde_yaml::from_str(&format!(r#"
+name: load_power
+value_type: integer
+device_class: "{device_class}"
+unit_of_measurement: "{unit}"
+icon: "mdi:flash"
+"#)).unwrap()
+        };
+        assert!(sensor("power", "W").validate().is_ok());
+        assert!(sensor("power", "kilowatt").validate().is_ok());
+        assert!(sensor("", "rpm").validate().is_ok());
+        assert!(sensor("duration", "s").validate().is_ok());
+        assert_eq!(
+            sensor("powr", "W").validate().unwrap_err().to_string(),
+            "'load_power' sensor: unknown device class 'powr'"
+        );
+        assert_eq!(
+            sensor("power", "V").validate().unwrap_err().to_string(),
+            "'load_power' sensor: unit 'V' does not match 'power' device class"
+        );
+
+        let mut sensor = sensor("", "");
+        sensor.value_type = ValueType::Boolean;
+        sensor.device_class = "running".to_string();
+        assert!(sensor.validate().is_ok());
+        sensor.device_class = "voltage".to_string();
+        assert!(sensor.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_influxdb() {
+        let influxdb: InfluxDbConfig = serde_yaml::from_str(r#"
+url: "http://localhost:8086"
+org: "home"
+bucket: "solar"
+token: "secret"
+"#).unwrap();
+        assert_eq!(influxdb.bucket, "solar");
+        assert_eq!(influxdb.measurement, "inverter");
+        assert_eq!(influxdb.max_buffered_lines, 1000);
+        assert_eq!(influxdb.timeout_ms, 5000);
+    }
+
+    #[test]
+    fn test_parse_mqtt_tls() {
+        let tls: MqttTls = serde_yaml::from_str(r#"
+ca_file: "/etc/ssl/ca.pem"
+client_cert: "/etc/ssl/client.pem"
+client_key: "/etc/ssl/client.key"
+"#).unwrap();
+        assert_eq!(tls.ca_file.unwrap().to_str(), Some("/etc/ssl/ca.pem"));
+        assert_eq!(tls.client_cert.unwrap().to_str(), Some("/etc/ssl/client.pem"));
+        assert_eq!(tls.client_key.unwrap().to_str(), Some("/etc/ssl/client.key"));
+        assert!(!tls.insecure_skip_verify);
+
+        let tls: MqttTls = serde_yaml::from_str(r#"
+ca_cert: "/etc/ssl/ca.pem"
+insecure_skip_verify: true
+"#).unwrap();
+        assert_eq!(tls.ca_file.unwrap().to_str(), Some("/etc/ssl/ca.pem"));
+        assert!(tls.insecure_skip_verify);
+    }
+
+    #[test]
+    fn test_parse_parser_config() {
+        let parser: ParserConfig = serde_yaml::from_str("type: binary").unwrap();
+        assert_eq!(parser, ParserConfig::Binary);
+        let parser: ParserConfig = serde_yaml::from_str("{type: fixed_width, widths: [5, 1]}")
+            .unwrap();
+        assert_eq!(parser, ParserConfig::FixedWidth(FixedWidthConfig { widths: vec![5, 1] }));
+    }
+
+    #[test]
+    fn test_parse_discovery_publish() {
+        let mqtt: MqttConfig = serde_yaml::from_str(r#"
+address: "localhost:1883"
+discovery_publish:
+  binary_sensor: {qos: 1}
+  number: {qos: 2, retain: false}
+"#).unwrap();
+        assert_eq!(
+            mqtt.discovery_publish[&Component::BinarySensor],
+            PublishOptions { qos: 1, retain: true }
+        );
+        assert_eq!(
+            mqtt.discovery_publish[&Component::Number],
+            PublishOptions { qos: 2, retain: false }
+        );
+        assert!(!mqtt.discovery_publish.contains_key(&Component::Sensor));
+        assert_eq!(mqtt.discovery_options(Component::Sensor), PublishOptions::default());
+
+        let mqtt: MqttConfig = serde_yaml::from_str(r#"
+address: "localhost:1883"
+discovery_qos: 1
+discovery_publish:
+  number: {qos: 2, retain: false}
+"#).unwrap();
+        assert_eq!(
+            mqtt.discovery_options(Component::Sensor),
+            PublishOptions { qos: 1, retain: true }
+        );
+        assert_eq!(
+            mqtt.discovery_options(Component::Number),
+            PublishOptions { qos: 2, retain: false }
+        );
+        assert_eq!((mqtt.state_qos, mqtt.state_retain), (0, false));
+    }
+
+    #[test]
+    fn test_drop_invalid_entries() {
+        let mut inverter: serde_yaml::Value = serde_yaml::from_str(r#"
+commands:
+- command: "QPIGS"
+  sensors:
+  - {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+  - {name: frequency, value_type: decimal, device_class: "", unit_of_measurement: "", icon: ""}
+  - null
+- command: "QMOD"
+  on_short_response: ignore
+  sensors:
+  - {name: mode, value_type: string, device_class: "", unit_of_measurement: "", icon: ""}
+derived_sensors:
+- {name: power, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+"#).unwrap();
+        let skipped = drop_invalid_entries(&mut inverter);
+        assert_eq!(skipped.len(), 3);
+        assert!(skipped[0].starts_with("Skipping sensor 'frequency'"), "{}", skipped[0]);
+        assert!(skipped[1].starts_with("Skipping command 'QMOD'"), "{}", skipped[1]);
+        assert!(skipped[2].starts_with("Skipping derived sensor 'power'"), "{}", skipped[2]);
+
+        let commands: Vec<CommandConfig> = serde_yaml::from_value(inverter["commands"].clone())
+            .unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].sensors.len(), 3);
+        assert!(commands[0].sensors[1].is_none());
+        assert!(inverter["derived_sensors"].as_sequence().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_crc_config() {
+        let crc: CrcConfig = serde_yaml::from_str(
+            "{poly: 0x1021, init: 0xffff, refin: true, refout: true, xorout: 0xffff}"
+        ).unwrap();
+        assert_eq!(
+            crc,
+            CrcConfig { poly: 0x1021, init: 0xffff, refin: true, refout: true, xorout: 0xffff }
+        );
+    }
+
+    #[test]
+    fn test_parse_ignored_sensors() {
+        let cmd: CommandConfig = serde_yaml::from_str(r#"
+command: "QPIGS"
+sensors:
+- {ignore: true}
+- null
+- {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+"#).unwrap();
+        assert!(cmd.sensors[0].is_none());
+        assert!(cmd.sensors[1].is_none());
+        assert_eq!(cmd.sensors[2].as_ref().unwrap().name, "voltage");
+
+        let err = serde_yaml::from_str::<CommandConfig>(r#"
+command: "QPIGS"
+sensors:
+- {ignore: true, name: voltage}
+"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field `ignore`"), "{err}");
+    }
+}
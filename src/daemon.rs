@@ -0,0 +1,137 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use snafu::{Whatever, whatever, ResultExt};
+
+use crate::inverter::{Inverter, InverterDevice};
+
+// Every reply is a single line starting with one of these
+const OK_PREFIX: &str = "ok ";
+const ERROR_PREFIX: &str = "error ";
+// Idle clients must not block the others forever
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Holds the device open and answers queries of clients one connection at a time
+pub fn listen<T: InverterDevice>(inverter: &mut Inverter<T>, path: &Path) -> Result<(), Whatever> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)
+        .with_whatever_context(|e| format!("Cannot listen on {}: {e}", path.display()))?;
+    log::info!("Listening on {}", path.display());
+    for stream in listener.incoming() {
+        let res = stream.and_then(|stream| handle_connection(inverter, &stream));
+        if let Err(e) = res {
+            log::warn!("Client connection failed: {e}");
+        }
+    }
+    Ok(())
+}
+
+// Socket of a killed daemon is left behind and would fail the bind
+fn remove_stale_socket(path: &Path) -> Result<(), Whatever> {
+    match UnixStream::connect(path) {
+        Ok(_) => whatever!("Daemon is already running on {}", path.display()),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).with_whatever_context(|e| {
+                format!("Cannot remove stale socket {}: {e}", path.display())
+            })
+        }
+        // Missing or not a socket, the bind reports the latter
+        Err(_) => Ok(()),
+    }
+}
+
+// Reads commands line by line and replies to each of them
+fn handle_connection<T: InverterDevice>(
+    inverter: &mut Inverter<T>,
+    stream: &UnixStream,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let cmd = line.trim();
+        if cmd.is_empty() {
+            continue;
+        }
+        let reply = match inverter.query(cmd) {
+            Ok(resp) => format!("{OK_PREFIX}{resp}"),
+            Err(e) => format!("{ERROR_PREFIX}{e}"),
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+// Sends a single command to the daemon and returns the inverter response
+pub fn query(path: &Path, cmd: &str) -> Result<String, Whatever> {
+    let mut stream = UnixStream::connect(path)
+        .with_whatever_context(|e| format!("Cannot connect to {}: {e}", path.display()))?;
+    writeln!(stream, "{cmd}")
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .with_whatever_context(|e| format!("Cannot send '{cmd}' command: {e}"))?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)
+        .with_whatever_context(|e| format!("Cannot read reply to '{cmd}' command: {e}"))?;
+    let reply = reply.strip_suffix('\n').unwrap_or(&reply);
+    if let Some(resp) = reply.strip_prefix(OK_PREFIX) {
+        return Ok(resp.to_string());
+    }
+    match reply.strip_prefix(ERROR_PREFIX) {
+        Some(e) => whatever!("'{cmd}' command failed: {e}"),
+        None => whatever!("Unexpected reply to '{cmd}' command: '{reply}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use crate::inverter::Inverter;
+    use crate::testutil::MockDevice;
+    use super::{handle_connection, query, remove_stale_socket};
+
+    #[test]
+    fn test_daemon_query() {
+        let path = std::env::temp_dir()
+            .join(format!("inverter2mqtt-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1d, 13, 0, 0, 0])
+        ).build();
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(&mut inverter, &stream).unwrap();
+            }
+        });
+        assert_eq!(query(&path, "QID").unwrap(), "0");
+        assert!(query(&path, "QID").unwrap_err().to_string().starts_with("'QID' command failed"));
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = std::env::temp_dir()
+            .join(format!("inverter2mqtt-stale-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        remove_stale_socket(&path).unwrap();
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let err = remove_stale_socket(&path).unwrap_err();
+        assert!(err.to_string().starts_with("Daemon is already running"), "{err}");
+        assert!(path.exists());
+
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
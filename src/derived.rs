@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::config::{DerivedRule, DerivedSensorConfig, RateRule, SignedRule};
+
+struct RateSample {
+    value: f64,
+    at: Instant,
+    rate: Option<f64>,
+}
+
+// Keeps samples of stateful rules between polling cycles
+#[derive(Default)]
+pub struct DerivedState {
+    rates: HashMap<String, RateSample>,
+}
+
+impl DerivedState {
+    // Returns None until all source sensors have known values
+    pub fn evaluate(
+        &mut self,
+        derived: &DerivedSensorConfig,
+        values: &HashMap<String, String>,
+        now: Instant,
+    ) -> Option<String> {
+        match &derived.rule {
+            DerivedRule::Signed(rule) => evaluate_signed(rule, values),
+            DerivedRule::Rate(rule) => self.evaluate_rate(&derived.sensor.name, rule, values, now),
+        }
+    }
+
+    // The first sample only starts the measurement
+    fn evaluate_rate(
+        &mut self,
+        name: &str,
+        rule: &RateRule,
+        values: &HashMap<String, String>,
+        now: Instant,
+    ) -> Option<String> {
+        let value = values.get(&rule.source)?.parse::<f64>().ok()?;
+        let Some(prev) = self.rates.get_mut(name) else {
+            self.rates.insert(name.to_string(), RateSample { value, at: now, rate: None });
+            return None;
+        };
+        let elapsed = now.duration_since(prev.at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return prev.rate.map(|rate| format!("{rate}"));
+        }
+        let delta = value - prev.value;
+        // Counter was reset so the delta is meaningless
+        let rate = if delta < 0.0 {
+            prev.rate.unwrap_or(0.0)
+        } else {
+            delta / elapsed * rule.scale
+        };
+        *prev = RateSample { value, at: now, rate: Some(rate) };
+        Some(format!("{rate}"))
+    }
+}
+
+fn evaluate_signed(rule: &SignedRule, values: &HashMap<String, String>) -> Option<String> {
+    let magnitude = values.get(&rule.magnitude)?.parse::<f64>().ok()?;
+    if !values.contains_key(&rule.negative_when.sensor) {
+        return None;
+    }
+    if rule.negative_when.is_met(values) && magnitude != 0.0 {
+        Some(format!("{}", -magnitude))
+    } else {
+        Some(format!("{magnitude}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::config::DerivedSensorConfig;
+    use super::DerivedState;
+
+    #[test]
+    fn test_evaluate_signed() {
+        let derived: DerivedSensorConfig = serde_yaml::from_str(r#"
+name: battery_signed_power
+value_type: float
+device_class: "power"
+unit_of_measurement: "W"
+icon: "mdi:battery"
+signed:
+  magnitude: battery_power
+  negative_when: {sensor: direction, equals: "1"}
+"#).unwrap();
+        let mut state = DerivedState::default();
+        let now = Instant::now();
+        let mut values = HashMap::new();
+        values.insert("battery_power".to_string(), "0450".to_string());
+        assert_eq!(state.evaluate(&derived, &values, now), None);
+
+        values.insert("direction".to_string(), "0".to_string());
+        assert_eq!(state.evaluate(&derived, &values, now), Some("450".to_string()));
+
+        values.insert("direction".to_string(), "1".to_string());
+        assert_eq!(state.evaluate(&derived, &values, now), Some("-450".to_string()));
+
+        values.insert("battery_power".to_string(), "0".to_string());
+        assert_eq!(state.evaluate(&derived, &values, now), Some("0".to_string()));
+    }
+
+    #[test]
+    fn test_evaluate_rate() {
+        let derived: DerivedSensorConfig = serde_yaml::from_str(r#"
+name: pv_power
+value_type: float
+device_class: "power"
+unit_of_measurement: "W"
+icon: "mdi:solar-power"
+rate:
+  source: pv_energy
+  scale: 3600000
+"#).unwrap();
+        let mut state = DerivedState::default();
+        let started_at = Instant::now();
+        let mut values = HashMap::new();
+        assert_eq!(state.evaluate(&derived, &values, started_at), None);
+
+        values.insert("pv_energy".to_string(), "12.5".to_string());
+        assert_eq!(state.evaluate(&derived, &values, started_at), None);
+
+        // 0.01 kWh during 30 seconds is 1200 W
+        values.insert("pv_energy".to_string(), "12.51".to_string());
+        let now = started_at + Duration::from_secs(30);
+        let rate = state.evaluate(&derived, &values, now).unwrap().parse::<f64>().unwrap();
+        assert!((rate - 1200.0).abs() < 1e-6, "{rate}");
+
+        // Reset counter keeps the previous rate
+        values.insert("pv_energy".to_string(), "0".to_string());
+        let now = started_at + Duration::from_secs(60);
+        let rate = state.evaluate(&derived, &values, now).unwrap().parse::<f64>().unwrap();
+        assert!((rate - 1200.0).abs() < 1e-6, "{rate}");
+
+        values.insert("pv_energy".to_string(), "0".to_string());
+        let now = started_at + Duration::from_secs(90);
+        assert_eq!(state.evaluate(&derived, &values, now), Some("0".to_string()));
+    }
+
+    #[test]
+    fn test_evaluate_rate_reset_before_first_rate() {
+        let derived: DerivedSensorConfig = serde_yaml::from_str(r#"
+name: pv_power
+value_type: float
+device_class: "power"
+unit_of_measurement: "W"
+icon: "mdi:solar-power"
+rate: {source: pv_energy}
+"#).unwrap();
+        let mut state = DerivedState::default();
+        let started_at = Instant::now();
+        let mut values = HashMap::new();
+        values.insert("pv_energy".to_string(), "10".to_string());
+        assert_eq!(state.evaluate(&derived, &values, started_at), None);
+        values.insert("pv_energy".to_string(), "2".to_string());
+        let now = started_at + Duration::from_secs(30);
+        assert_eq!(state.evaluate(&derived, &values, now), Some("0".to_string()));
+    }
+}
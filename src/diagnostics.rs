@@ -0,0 +1,101 @@
+use crate::inverter::InverterError;
+
+// Operational counters since the process start
+#[derive(Default)]
+pub struct Diagnostics {
+    successful_polls: u64,
+    crc_failures: u64,
+    timeouts: u64,
+    device_errors: u64,
+    // Reset by a successful poll
+    consecutive_crc_failures: u64,
+}
+
+impl Diagnostics {
+    pub fn record<T>(&mut self, res: &Result<T, InverterError>) {
+        match res {
+            Ok(_) => {
+                self.successful_polls += 1;
+                self.consecutive_crc_failures = 0;
+            }
+            Err(InverterError::InvalidCrc { .. }) => {
+                self.crc_failures += 1;
+                self.consecutive_crc_failures += 1;
+            }
+            Err(InverterError::Device { source }) if source.is_timeout() => self.timeouts += 1,
+            Err(InverterError::Device { .. }) => self.device_errors += 1,
+            Err(_) => {}
+        }
+    }
+
+    pub fn consecutive_crc_failures(&self) -> u64 {
+        self.consecutive_crc_failures
+    }
+
+    // Sensor names with the counter values
+    pub fn values(&self) -> [(&'static str, u64); 4] {
+        [
+            ("successful_polls", self.successful_polls),
+            ("crc_failures", self.crc_failures),
+            ("timeouts", self.timeouts),
+            ("device_errors", self.device_errors),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::inverter::{DeviceError, Inverter, InverterError};
+    use crate::testutil::MockDevice;
+    use super::Diagnostics;
+
+    #[test]
+    fn test_diagnostics_record() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.record(&Ok::<(), InverterError>(()));
+        diagnostics.record(&Ok::<(), InverterError>(()));
+        diagnostics.record::<()>(&Err(InverterError::InvalidCrc {
+            expected: "0x0000".to_string(),
+            actual: "0x0001".to_string(),
+            data: String::new(),
+        }));
+        diagnostics.record::<()>(&Err(InverterError::Device {
+            source: DeviceError::Usb { source: rusb::Error::Timeout },
+        }));
+        diagnostics.record::<()>(&Err(InverterError::Device {
+            source: DeviceError::Io { source: ErrorKind::TimedOut.into() },
+        }));
+        diagnostics.record::<()>(&Err(InverterError::Device {
+            source: DeviceError::Usb { source: rusb::Error::NoDevice },
+        }));
+        diagnostics.record::<()>(&Err(InverterError::MissingResponseMarker));
+        assert_eq!(diagnostics.consecutive_crc_failures(), 1);
+        assert_eq!(
+            diagnostics.values(),
+            [("successful_polls", 2), ("crc_failures", 1), ("timeouts", 2), ("device_errors", 1)]
+        );
+        diagnostics.record(&Ok::<(), InverterError>(()));
+        assert_eq!(diagnostics.consecutive_crc_failures(), 0);
+    }
+
+    #[test]
+    fn test_diagnostics_usb_errors() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .fail_send(DeviceError::Usb { source: rusb::Error::NoDevice })
+                .fail_read(DeviceError::Usb { source: rusb::Error::Pipe })
+                .timeout_read()
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+        ).build();
+        let mut diagnostics = Diagnostics::default();
+        for _ in 0..4 {
+            diagnostics.record(&inverter.query("QID"));
+        }
+        assert_eq!(
+            diagnostics.values(),
+            [("successful_polls", 1), ("crc_failures", 0), ("timeouts", 1), ("device_errors", 2)]
+        );
+    }
+}
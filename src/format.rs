@@ -0,0 +1,102 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+type Formatter = fn(&str) -> Option<String>;
+
+// Formatters converting raw values into representations Home Assistant expects
+const FORMATTERS: &[(&str, Formatter)] = &[
+    ("timestamp", format_timestamp),
+    ("date", format_date),
+];
+
+// Values that cannot be converted are published as is
+pub fn format_value(device_class: &str, value: &str) -> String {
+    FORMATTERS.iter()
+        .find(|(class, _)| *class == device_class)
+        .and_then(|(_, formatter)| formatter(value))
+        .unwrap_or_else(|| value.to_string())
+}
+
+// Unit Home Assistant expects with its lowercase spellings
+type Spellings = (&'static str, &'static [&'static str]);
+
+// Units Home Assistant converts for a device class
+const UNITS: &[(&str, &[Spellings])] = &[
+    ("voltage", &[("V", &["v", "volt", "volts"]), ("mV", &["mv", "millivolt", "millivolts"])]),
+    ("current", &[("A", &["a", "amp", "amps", "ampere"]), ("mA", &["ma", "milliamp"])]),
+    ("power", &[("W", &["w", "watt", "watts"]), ("kW", &["kw", "kilowatt", "kilowatts"])]),
+    ("apparent_power", &[("VA", &["va"])]),
+    ("reactive_power", &[("var", &["var"])]),
+    ("energy", &[("Wh", &["wh"]), ("kWh", &["kwh"])]),
+    ("frequency", &[("Hz", &["hz", "hertz"])]),
+    ("temperature", &[
+        ("°C", &["°c", "c", "degc", "celsius"]),
+        ("°F", &["°f", "f", "fahrenheit"]),
+    ]),
+    ("battery", &[("%", &["%", "percent"])]),
+];
+
+pub enum Unit {
+    // Recognized unit in the spelling Home Assistant expects
+    Known(&'static str),
+    // Home Assistant would warn about the unit
+    Unknown,
+    // Units of the device class are not checked
+    Unchecked,
+}
+
+pub fn normalize_unit(device_class: &str, unit: &str) -> Unit {
+    let Some((_, units)) = UNITS.iter().find(|(class, _)| *class == device_class) else {
+        return Unit::Unchecked;
+    };
+    let unit = unit.trim().to_lowercase();
+    units.iter()
+        .find(|(_, spellings)| spellings.contains(&unit.as_str()))
+        .map_or(Unit::Unknown, |(known, _)| Unit::Known(known))
+}
+
+fn parse_unix_epoch(value: &str) -> Option<DateTime<Utc>> {
+    let secs = value.parse::<i64>().ok()
+        .or_else(|| value.parse::<f64>().ok().map(|v| v as i64))?;
+    DateTime::from_timestamp(secs, 0)
+}
+
+fn format_timestamp(value: &str) -> Option<String> {
+    parse_unix_epoch(value)
+        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn format_date(value: &str) -> Option<String> {
+    parse_unix_epoch(value)
+        .map(|dt| dt.date_naive().format("%Y-%m-%d").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_value, normalize_unit, Unit};
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_value("timestamp", "1700000000"), "2023-11-14T22:13:20Z");
+        assert_eq!(format_value("timestamp", "2023-11-14T22:13:20Z"), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_value("date", "1700000000"), "2023-11-14");
+    }
+
+    #[test]
+    fn test_format_other_device_class() {
+        assert_eq!(format_value("voltage", "1700000000"), "1700000000");
+    }
+
+    #[test]
+    fn test_normalize_unit() {
+        assert!(matches!(normalize_unit("voltage", "volt"), Unit::Known("V")));
+        assert!(matches!(normalize_unit("voltage", "mV"), Unit::Known("mV")));
+        assert!(matches!(normalize_unit("power", "KW"), Unit::Known("kW")));
+        assert!(matches!(normalize_unit("temperature", "C"), Unit::Known("°C")));
+        assert!(matches!(normalize_unit("voltage", "W"), Unit::Unknown));
+        assert!(matches!(normalize_unit("", "rpm"), Unit::Unchecked));
+    }
+}
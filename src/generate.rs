@@ -0,0 +1,119 @@
+use crate::config::{RequestParams, ResponseParams, UsbConfig};
+
+// Well-known queries answered by most Voltronic-compatible inverters
+pub const PROBE_COMMANDS: &[&str] = &["QPI", "QID", "QVFW", "QPIRI", "QPIGS", "QMOD", "QPIWS"];
+
+// Parses a VID:PID pair in hex, e.g. 0665:5161
+pub fn parse_usb_id(s: &str) -> Result<(u16, u16), String> {
+    let (vendor_id, product_id) = s.split_once(':')
+        .ok_or_else(|| format!("expected VID:PID, got '{s}'"))?;
+    let parse_hex = |v: &str| {
+        u16::from_str_radix(v.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("invalid USB id '{v}': {e}"))
+    };
+    Ok((parse_hex(vendor_id)?, parse_hex(product_id)?))
+}
+
+// Transfer parameters of the Cypress USB-serial bridge used by most inverters
+pub fn default_usb_config(vendor_id: u16, product_id: u16) -> UsbConfig {
+    UsbConfig {
+        vendor_id,
+        product_id,
+        device_path: None,
+        interface: 0,
+        request_params: RequestParams {
+            request_type: 0x21,
+            request: 0x9,
+            value: 0x200,
+            index: 0,
+            timeout_ms: 100,
+        },
+        response_params: ResponseParams {
+            endpoint: 0x81,
+            timeout_ms: 100,
+        },
+    }
+}
+
+// Builds a config skeleton with a placeholder sensor for every response field
+pub fn scaffold_config(usb_cfg: &UsbConfig, responses: &[(&str, &str)]) -> String {
+    let req = &usb_cfg.request_params;
+    let resp = &usb_cfg.response_params;
+    let mut out = format!(r#"inverter:
+  id: "inverter"
+  name: "Inverter"
+  manufacturer: "Unknown"
+  model: "Unknown"
+  usb:
+    vendor_id: {:#06x}
+    product_id: {:#06x}
+    interface: {}
+    request_params:
+      request_type: {:#x}
+      request: {:#x}
+      value: {:#x}
+      index: {}
+      timeout_ms: {}
+    response_params:
+      endpoint: {:#x}
+      timeout_ms: {}
+  commands:
+"#,
+        usb_cfg.vendor_id, usb_cfg.product_id, usb_cfg.interface,
+        req.request_type, req.request, req.value, req.index, req.timeout_ms,
+        resp.endpoint, resp.timeout_ms,
+    );
+    for (command, response) in responses {
+        out.push_str(&format!("  # {command} -> '{response}'\n"));
+        out.push_str(&format!("  - command: \"{command}\"\n"));
+        let fields_count = response.split_ascii_whitespace().count();
+        if fields_count == 0 {
+            out.push_str("    sensors: []\n");
+            continue;
+        }
+        out.push_str("    sensors:\n");
+        for i in 1..=fields_count {
+            out.push_str(&format!(r#"    - name: {}_field_{i}
+      value_type: string
+      device_class: ""
+      unit_of_measurement: ""
+      icon: ""
+"#,
+                command.to_lowercase(),
+            ));
+        }
+    }
+    out.push_str("mqtt:\n  address: \"localhost:1883\"\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, DeviceConfig};
+    use super::{default_usb_config, parse_usb_id, scaffold_config};
+
+    #[test]
+    fn test_parse_usb_id() {
+        assert_eq!(parse_usb_id("0665:5161"), Ok((0x0665, 0x5161)));
+        assert_eq!(parse_usb_id("0x0665:0x5161"), Ok((0x0665, 0x5161)));
+        assert!(parse_usb_id("0665").is_err());
+        assert!(parse_usb_id("0665:xyz").is_err());
+    }
+
+    #[test]
+    fn test_scaffold_config() {
+        let scaffold = scaffold_config(
+            &default_usb_config(0x0665, 0x5161),
+            &[("QPIGS", "230.0 50.0 0000"), ("QMOD", "L"), ("QPIWS", "")],
+        );
+        let config: Config = serde_yaml::from_str(&scaffold).unwrap();
+        let DeviceConfig::Usb(usb) = &config.inverters[0].device else {
+            panic!("Expected usb device config");
+        };
+        assert_eq!((usb.vendor_id, usb.product_id), (0x0665, 0x5161));
+        assert_eq!(config.inverters[0].commands.len(), 3);
+        assert_eq!(config.inverters[0].commands[0].sensors.len(), 3);
+        assert_eq!(config.inverters[0].commands[1].sensors.len(), 1);
+        assert_eq!(config.inverters[0].commands[2].sensors.len(), 0);
+    }
+}
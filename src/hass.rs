@@ -1,4 +1,59 @@
 use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::config::{DiscoveryFormat, StateClass};
+
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("availability", "avty"),
+    ("availability_mode", "avty_mode"),
+    ("availability_topic", "avty_t"),
+    ("command_topic", "cmd_t"),
+    ("device", "dev"),
+    ("device_class", "dev_cla"),
+    ("entity_category", "ent_cat"),
+    ("icon", "ic"),
+    ("json_attributes_topic", "json_attr_t"),
+    ("last_reset_value_template", "last_reset_val_tpl"),
+    ("object_id", "obj_id"),
+    ("options", "ops"),
+    ("payload_available", "pl_avail"),
+    ("payload_not_available", "pl_not_avail"),
+    ("payload_off", "pl_off"),
+    ("payload_on", "pl_on"),
+    ("state_class", "stat_cla"),
+    ("state_topic", "stat_t"),
+    ("unique_id", "uniq_id"),
+    ("unit_of_measurement", "unit_of_meas"),
+    ("value_template", "val_tpl"),
+];
+
+const DEVICE_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("identifiers", "ids"),
+    ("manufacturer", "mf"),
+    ("model", "mdl"),
+    ("sw_version", "sw"),
+];
+
+// Device classes documented by Home Assistant, entities with other ones are ignored
+pub const SENSOR_DEVICE_CLASSES: &[&str] = &[
+    "absolute_humidity", "apparent_power", "aqi", "area", "atmospheric_pressure", "battery",
+    "blood_glucose_concentration", "carbon_dioxide", "carbon_monoxide", "conductivity",
+    "current", "data_rate", "data_size", "date", "distance", "duration", "energy",
+    "energy_distance", "energy_storage", "enum", "frequency", "gas", "humidity", "illuminance",
+    "irradiance", "moisture", "monetary", "nitrogen_dioxide", "nitrogen_monoxide",
+    "nitrous_oxide", "ozone", "ph", "pm1", "pm10", "pm25", "power", "power_factor",
+    "precipitation", "precipitation_intensity", "pressure", "reactive_energy", "reactive_power",
+    "signal_strength", "sound_pressure", "speed", "sulphur_dioxide", "temperature", "timestamp",
+    "volatile_organic_compounds", "volatile_organic_compounds_parts", "voltage", "volume",
+    "volume_flow_rate", "volume_storage", "water", "weight", "wind_direction", "wind_speed",
+];
+
+pub const BINARY_SENSOR_DEVICE_CLASSES: &[&str] = &[
+    "battery", "battery_charging", "carbon_monoxide", "cold", "connectivity", "door",
+    "garage_door", "gas", "heat", "light", "lock", "moisture", "motion", "moving", "occupancy",
+    "opening", "plug", "power", "presence", "problem", "running", "safety", "smoke", "sound",
+    "tamper", "update", "vibration", "window",
+];
 
 #[derive(Serialize)]
 pub struct Discovery {
@@ -6,10 +61,117 @@ pub struct Discovery {
     pub object_id: String,
     pub unique_id: String,
     pub state_topic: String,
+    #[serde(flatten)]
+    pub availability: Option<Availability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
     pub device: Device,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub device_class: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub unit_of_measurement: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub icon: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<EntityCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<StateClass>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reset_value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    // Topic Home Assistant publishes changes of writable entities to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    // Choices of a select entity
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl Discovery {
+    pub fn to_json(
+        &self,
+        format: DiscoveryFormat,
+        base_topic: &str,
+    ) -> Result<String, serde_json::Error> {
+        match format {
+            DiscoveryFormat::Verbose => serde_json::to_string(self),
+            DiscoveryFormat::Abbreviated => {
+                let Value::Object(fields) = serde_json::to_value(self)? else {
+                    unreachable!("discovery is always serialized as an object");
+                };
+                let mut abbreviated = Map::new();
+                abbreviated.insert("~".to_string(), Value::String(base_topic.to_string()));
+                abbreviated.extend(abbreviate(fields, ABBREVIATIONS, base_topic));
+                serde_json::to_string(&abbreviated)
+            }
+        }
+    }
+}
+
+fn abbreviate(
+    fields: Map<String, Value>,
+    abbreviations: &[(&str, &str)],
+    base_topic: &str,
+) -> Map<String, Value> {
+    fields.into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Object(device) if key == "device" => {
+                    Value::Object(abbreviate(device, DEVICE_ABBREVIATIONS, base_topic))
+                }
+                Value::String(topic) if key.ends_with("_topic") => {
+                    match topic.strip_prefix(base_topic) {
+                        Some(rest) if rest.starts_with('/') => Value::String(format!("~{rest}")),
+                        _ => Value::String(topic),
+                    }
+                }
+                value => value,
+            };
+            let key = abbreviations.iter()
+                .find(|(full, _)| *full == key)
+                .map(|(_, short)| short.to_string())
+                .unwrap_or(key);
+            (key, value)
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Availability {
+    Topic { availability_topic: String },
+    // Entity is available only while all of the topics are online
+    All { availability: Vec<AvailabilityTopic>, availability_mode: &'static str },
+}
+
+impl Availability {
+    pub fn new(topic: String, shared_topic: Option<&str>) -> Self {
+        match shared_topic {
+            None => Self::Topic { availability_topic: topic },
+            Some(shared_topic) => Self::all(vec![topic, shared_topic.to_string()]),
+        }
+    }
+
+    pub fn all(topics: Vec<String>) -> Self {
+        Self::All {
+            availability: topics.into_iter().map(|topic| AvailabilityTopic { topic }).collect(),
+            availability_mode: "all",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AvailabilityTopic {
+    pub topic: String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub enum EntityCategory {
+    #[serde(rename = "config")]
+    Config,
+    #[serde(rename = "diagnostic")]
+    Diagnostic,
 }
 
 #[derive(Clone, Serialize)]
@@ -18,4 +180,198 @@ pub struct Device {
     pub identifiers: Vec<String>,
     pub manufacturer: String,
     pub model: String,
+    // Identifier of the parent device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{DiscoveryFormat, StateClass};
+    use super::{Availability, Device, Discovery, EntityCategory};
+
+    #[test]
+    fn test_discovery_abbreviated() {
+        let discovery = Discovery {
+            name: "Grid Voltage".to_string(),
+            object_id: "powmr_grid_voltage".to_string(),
+            unique_id: "powmr_grid_voltage".to_string(),
+            state_topic: "homeassistant/sensor/powmr/powmr_grid_voltage/state".to_string(),
+            availability: Some(Availability::new(
+                "homeassistant/sensor/powmr/availability".to_string(), None
+            )),
+            json_attributes_topic: None,
+            device: Device {
+                name: "PowMr Inverter".to_string(),
+                identifiers: vec!["powmr".to_string()],
+                manufacturer: "PowMr".to_string(),
+                model: "PowMr 5000W".to_string(),
+                via_device: None,
+            },
+            device_class: "voltage".to_string(),
+            unit_of_measurement: "V".to_string(),
+            icon: "mdi:power-plug".to_string(),
+            entity_category: None,
+            state_class: Some(StateClass::Measurement),
+            last_reset_value_template: None,
+            value_template: Some("{{ value | round(1) }}".to_string()),
+            command_topic: None,
+            options: vec!(),
+        };
+        assert_eq!(
+            discovery.to_json(
+                DiscoveryFormat::Abbreviated,
+                "homeassistant/sensor/powmr/powmr_grid_voltage"
+            ).unwrap(),
+            concat!(
+                r#"{"avty_t":"homeassistant/sensor/powmr/availability","#,
+                r#""dev":{"ids":["powmr"],"mdl":"PowMr 5000W","mf":"PowMr","name":"PowMr Inverter"},"#,
+                r#""dev_cla":"voltage","ic":"mdi:power-plug","name":"Grid Voltage","#,
+                r#""obj_id":"powmr_grid_voltage","stat_cla":"measurement","stat_t":"~/state","#,
+                r#""uniq_id":"powmr_grid_voltage","unit_of_meas":"V","#,
+                r#""val_tpl":"{{ value | round(1) }}","#,
+                r#""~":"homeassistant/sensor/powmr/powmr_grid_voltage"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_discovery_full() {
+        let discovery = Discovery {
+            name: "Output Source Priority".to_string(),
+            object_id: "powmr_output_source_priority".to_string(),
+            unique_id: "powmr_output_source_priority".to_string(),
+            state_topic: "homeassistant/select/powmr/powmr_output_source_priority/state".to_string(),
+            availability: Some(Availability::new(
+                "homeassistant/sensor/powmr/availability".to_string(), None
+            )),
+            json_attributes_topic: Some(
+                "homeassistant/select/powmr/powmr_output_source_priority/attributes".to_string()
+            ),
+            device: Device {
+                name: "PowMr Charger".to_string(),
+                identifiers: vec!["powmr_charger".to_string()],
+                manufacturer: "PowMr".to_string(),
+                model: "PowMr 5000W".to_string(),
+                via_device: Some("powmr".to_string()),
+            },
+            device_class: "enum".to_string(),
+            unit_of_measurement: "W".to_string(),
+            icon: "mdi:transmission-tower".to_string(),
+            entity_category: Some(EntityCategory::Config),
+            state_class: Some(StateClass::Total),
+            last_reset_value_template: Some("{{ value_json.last_reset }}".to_string()),
+            value_template: Some("{{ value }}".to_string()),
+            command_topic: Some(
+                "homeassistant/select/powmr/powmr_output_source_priority/set".to_string()
+            ),
+            options: vec!("Utility".to_string(), "SBU".to_string()),
+        };
+        assert_eq!(
+            discovery.to_json(DiscoveryFormat::Verbose, "").unwrap(),
+            concat!(
+                r#"{"name":"Output Source Priority","#,
+                r#""object_id":"powmr_output_source_priority","#,
+                r#""unique_id":"powmr_output_source_priority","#,
+                r#""state_topic":"homeassistant/select/powmr/powmr_output_source_priority/state","#,
+                r#""availability_topic":"homeassistant/sensor/powmr/availability","#,
+                r#""json_attributes_topic":"#,
+                r#""homeassistant/select/powmr/powmr_output_source_priority/attributes","#,
+                r#""device":{"name":"PowMr Charger","identifiers":["powmr_charger"],"#,
+                r#""manufacturer":"PowMr","model":"PowMr 5000W","via_device":"powmr"},"#,
+                r#""device_class":"enum","unit_of_measurement":"W","#,
+                r#""icon":"mdi:transmission-tower","entity_category":"config","#,
+                r#""state_class":"total","#,
+                r#""last_reset_value_template":"{{ value_json.last_reset }}","#,
+                r#""value_template":"{{ value }}","#,
+                r#""command_topic":"homeassistant/select/powmr/powmr_output_source_priority/set","#,
+                r#""options":["Utility","SBU"]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_discovery_shared_availability() {
+        let discovery = Discovery {
+            name: "Load Power".to_string(),
+            object_id: "first_load_power".to_string(),
+            unique_id: "first_load_power".to_string(),
+            state_topic: "homeassistant/sensor/first/first_load_power/state".to_string(),
+            availability: Some(Availability::new(
+                "homeassistant/sensor/first/availability".to_string(),
+                Some("inverter2mqtt/bridge/availability"),
+            )),
+            json_attributes_topic: None,
+            device: Device {
+                name: "First Inverter".to_string(),
+                identifiers: vec!["first".to_string()],
+                manufacturer: "PowMr".to_string(),
+                model: "PowMr 5000W".to_string(),
+                via_device: None,
+            },
+            device_class: String::new(),
+            unit_of_measurement: String::new(),
+            icon: String::new(),
+            entity_category: None,
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: vec!(),
+        };
+        assert_eq!(
+            discovery.to_json(
+                DiscoveryFormat::Abbreviated,
+                "homeassistant/sensor/first/first_load_power"
+            ).unwrap(),
+            concat!(
+                r#"{"avty":[{"topic":"homeassistant/sensor/first/availability"},"#,
+                r#"{"topic":"inverter2mqtt/bridge/availability"}],"avty_mode":"all","#,
+                r#""dev":{"ids":["first"],"mdl":"PowMr 5000W","mf":"PowMr","#,
+                r#""name":"First Inverter"},"#,
+                r#""name":"Load Power","obj_id":"first_load_power","stat_t":"~/state","#,
+                r#""uniq_id":"first_load_power","#,
+                r#""~":"homeassistant/sensor/first/first_load_power"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_discovery_diagnostic() {
+        let discovery = Discovery {
+            name: "Timeouts".to_string(),
+            object_id: "powmr_timeouts".to_string(),
+            unique_id: "powmr_timeouts".to_string(),
+            state_topic: "homeassistant/sensor/powmr/powmr_timeouts/state".to_string(),
+            availability: None,
+            json_attributes_topic: None,
+            device: Device {
+                name: "PowMr Inverter".to_string(),
+                identifiers: vec!["powmr".to_string()],
+                manufacturer: "PowMr".to_string(),
+                model: "PowMr 5000W".to_string(),
+                via_device: None,
+            },
+            device_class: String::new(),
+            unit_of_measurement: String::new(),
+            icon: "mdi:timer-alert".to_string(),
+            entity_category: Some(EntityCategory::Diagnostic),
+            state_class: Some(StateClass::TotalIncreasing),
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: vec!(),
+        };
+        assert_eq!(
+            discovery.to_json(DiscoveryFormat::Verbose, "").unwrap(),
+            concat!(
+                r#"{"name":"Timeouts","object_id":"powmr_timeouts","unique_id":"powmr_timeouts","#,
+                r#""state_topic":"homeassistant/sensor/powmr/powmr_timeouts/state","#,
+                r#""device":{"name":"PowMr Inverter","identifiers":["powmr"],"#,
+                r#""manufacturer":"PowMr","model":"PowMr 5000W"},"#,
+                r#""icon":"mdi:timer-alert","entity_category":"diagnostic","#,
+                r#""state_class":"total_increasing"}"#,
+            )
+        );
+    }
 }
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use snafu::{Whatever, whatever};
+
+use crate::config::InfluxDbConfig;
+use crate::inverter::SensorValue;
+
+const RETRY_MIN_DELAY_SECS: u64 = 10;
+const RETRY_MAX_DELAY_SECS: u64 = 300;
+
+// Writes sensor values as line protocol, lines are buffered while the server is unreachable
+pub struct InfluxDbWriter<'a> {
+    cfg: &'a InfluxDbConfig,
+    agent: ureq::Agent,
+    write_url: String,
+    inverter_id: String,
+    buffer: VecDeque<String>,
+    retry_delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl<'a> InfluxDbWriter<'a> {
+    pub fn new(cfg: &'a InfluxDbConfig, inverter_id: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(cfg.timeout_ms))
+            .build();
+        Self {
+            cfg,
+            agent,
+            write_url: format!("{}/api/v2/write", cfg.url.trim_end_matches('/')),
+            inverter_id: inverter_id.to_string(),
+            buffer: VecDeque::new(),
+            retry_delay: Duration::from_secs(RETRY_MIN_DELAY_SECS),
+            retry_at: None,
+        }
+    }
+
+    // Values of a single cycle go to one line, timestamped when they are buffered
+    pub fn write(&mut self, values: &HashMap<String, SensorValue>) -> Result<(), Whatever> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let tags = [("inverter", self.inverter_id.as_str())];
+        if let Some(line) = format_line(&self.cfg.measurement, &tags, values, timestamp) {
+            self.buffer.push_back(line);
+        }
+        let mut dropped = 0;
+        while self.buffer.len() > self.cfg.max_buffered_lines {
+            self.buffer.pop_front();
+            dropped += 1;
+        }
+        if dropped > 0 {
+            log::warn!("InfluxDB buffer is full, dropped {dropped} oldest lines");
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), Whatever> {
+        if self.buffer.is_empty() || self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        let body = self.buffer.iter().map(String::as_str).collect::<Vec<_>>().join("\n");
+        let res = self.agent.post(&self.write_url)
+            .query("org", &self.cfg.org)
+            .query("bucket", &self.cfg.bucket)
+            .query("precision", "s")
+            .set("Authorization", &format!("Token {}", self.cfg.token))
+            .set("Content-Type", "text/plain; charset=utf-8")
+            .send_string(&body);
+        match res {
+            Ok(_) => {
+                self.buffer.clear();
+                self.retry_delay = Duration::from_secs(RETRY_MIN_DELAY_SECS);
+                self.retry_at = None;
+                Ok(())
+            }
+            // Retrying lines the server refuses to accept would only block the following ones
+            Err(ureq::Error::Status(code, resp)) if is_permanent_failure(code) => {
+                let lines = self.buffer.len();
+                self.buffer.clear();
+                let msg = resp.into_string().unwrap_or_default();
+                whatever!("InfluxDB rejected {lines} lines with status {code}, dropped them: {msg}")
+            }
+            Err(e) => {
+                self.retry_at = Some(Instant::now() + self.retry_delay);
+                self.retry_delay = (self.retry_delay * 2)
+                    .min(Duration::from_secs(RETRY_MAX_DELAY_SECS));
+                match e {
+                    ureq::Error::Status(code, resp) => {
+                        let msg = resp.into_string().unwrap_or_default();
+                        whatever!(
+                            "InfluxDB rejected {} lines with status {code}: {msg}",
+                            self.buffer.len()
+                        )
+                    }
+                    e => whatever!("Cannot write {} lines to InfluxDB: {e}", self.buffer.len()),
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn buffered_lines(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+// Malformed or oversized lines are not fixed by sending them again, while auth and
+// missing bucket errors are fixed on the server and the lines are retried
+fn is_permanent_failure(status: u16) -> bool {
+    matches!(status, 400 | 413 | 422)
+}
+
+// None when none of the values can be written
+pub fn format_line(
+    measurement: &str,
+    tags: &[(&str, &str)],
+    values: &HashMap<String, SensorValue>,
+    timestamp: u64,
+) -> Option<String> {
+    // Sorted fields keep lines of consecutive cycles comparable
+    let mut values = values.iter().collect::<Vec<_>>();
+    values.sort_by_key(|(name, _)| name.as_str());
+    let fields = values.into_iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                SensorValue::Integer(v) => format!("{v}i"),
+                SensorValue::Float(v) if v.is_finite() => format!("{v}"),
+                SensorValue::Float(_) => return None,
+                SensorValue::String(v) => {
+                    format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+                SensorValue::Boolean(v) => v.to_string(),
+                SensorValue::Unavailable => return None,
+            };
+            Some(format!("{}={value}", escape_key(name)))
+        })
+        .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut line = measurement.replace(',', "\\,").replace(' ', "\\ ");
+    for (key, value) in tags {
+        line.push_str(&format!(",{}={}", escape_key(key), escape_key(value)));
+    }
+    Some(format!("{line} {} {timestamp}", fields.join(",")))
+}
+
+// Tag keys, tag values and field keys share the escaping rules
+fn escape_key(key: &str) -> String {
+    key.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::{format_line, InfluxDbWriter};
+    use crate::config::InfluxDbConfig;
+    use crate::inverter::SensorValue;
+
+    #[test]
+    fn test_format_line() {
+        let values = HashMap::from([
+            ("battery_voltage".to_string(), SensorValue::Float(26.5)),
+            ("load_power".to_string(), SensorValue::Integer(350)),
+            ("mode".to_string(), SensorValue::String("Line \"AC\"".to_string())),
+            ("charging".to_string(), SensorValue::Boolean(true)),
+            ("pv_power".to_string(), SensorValue::Unavailable),
+            ("pv_current".to_string(), SensorValue::Float(f64::NAN)),
+        ]);
+        assert_eq!(
+            format_line("inverter", &[("inverter", "my powmr")], &values, 1700000000).unwrap(),
+            "inverter,inverter=my\\ powmr battery_voltage=26.5,charging=true,load_power=350i,\
+            mode=\"Line \\\"AC\\\"\" 1700000000"
+        );
+
+        let values = HashMap::from([("pv_power".to_string(), SensorValue::Unavailable)]);
+        assert_eq!(format_line("inverter", &[], &values, 1700000000), None);
+    }
+
+    #[test]
+    fn test_buffer_while_unreachable() {
+        let cfg = InfluxDbConfig {
+            // Nothing listens on port 1
+            url: "http://127.0.0.1:1/".to_string(),
+            org: "home".to_string(),
+            bucket: "solar".to_string(),
+            token: "secret".to_string(),
+            measurement: "inverter".to_string(),
+            max_buffered_lines: 2,
+            timeout_ms: 500,
+        };
+        let mut writer = InfluxDbWriter::new(&cfg, "powmr");
+        let values = HashMap::from([("load_power".to_string(), SensorValue::Integer(350))]);
+        assert!(writer.write(&values).is_err());
+        assert_eq!(writer.buffered_lines(), 1);
+        // Backing off, so the next lines are only buffered
+        assert!(writer.write(&values).is_ok());
+        assert!(writer.write(&values).is_ok());
+        assert_eq!(writer.buffered_lines(), 2);
+    }
+
+    fn local_config(listener: &TcpListener) -> InfluxDbConfig {
+        InfluxDbConfig {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            org: "home".to_string(),
+            bucket: "solar".to_string(),
+            token: "secret".to_string(),
+            measurement: "inverter".to_string(),
+            max_buffered_lines: 10,
+            timeout_ms: 500,
+        }
+    }
+
+    // Answers the given number of single line writes with the status
+    fn respond_status(
+        listener: TcpListener,
+        requests: usize,
+        status: &'static str,
+        msg: &'static str,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+                assert!(body.starts_with("inverter,inverter=powmr load_power=350i"), "{body}");
+                assert_eq!(body.lines().count(), 1);
+                write!(
+                    &stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{msg}",
+                    msg.len()
+                ).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_drop_rejected_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = local_config(&listener);
+        let server = respond_status(listener, 2, "400 Bad Request", "invalid field");
+        let mut writer = InfluxDbWriter::new(&cfg, "powmr");
+        let values = HashMap::from([("load_power".to_string(), SensorValue::Integer(350))]);
+        let err = writer.write(&values).unwrap_err();
+        assert!(err.to_string().contains("status 400, dropped them: invalid field"), "{err}");
+        assert_eq!(writer.buffered_lines(), 0);
+        // Not backing off, the next line is sent right away and without the rejected one
+        assert!(writer.write(&values).is_err());
+        assert_eq!(writer.buffered_lines(), 0);
+        server.join().unwrap();
+    }
+    #[test]
+    fn test_retry_unauthorized() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = local_config(&listener);
+        let server = respond_status(listener, 1, "401 Unauthorized", "unauthorized access");
+        let mut writer = InfluxDbWriter::new(&cfg, "powmr");
+        let values = HashMap::from([("load_power".to_string(), SensorValue::Integer(350))]);
+        let err = writer.write(&values).unwrap_err();
+        assert!(err.to_string().contains("status 401: unauthorized access"), "{err}");
+        assert_eq!(writer.buffered_lines(), 1);
+        // Backing off until the token is fixed, the lines are kept
+        assert!(writer.write(&values).is_ok());
+        assert_eq!(writer.buffered_lines(), 2);
+        server.join().unwrap();
+    }
+}
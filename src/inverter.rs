@@ -1,27 +1,75 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::num::{ParseFloatError, ParseIntError};
+use std::io::{Error as IoError, ErrorKind};
 use std::str::{self, Utf8Error};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use crc::{Crc, CRC_16_XMODEM};
+use crc::{Algorithm, Crc, CRC_16_IBM_3740, CRC_16_MODBUS, CRC_16_XMODEM};
 
 use rusb::Error as UsbError;
 
+use serde::Serialize;
+
 use snafu::Snafu;
 use snafu::prelude::*;
 
-use crate::config::CommandConfig;
-use crate::config::ValueType;
+use crate::config::{
+    BitConfig, CommandConfig, CrcAlgorithm, CrcConfig, Endian, FramingConfig, LengthPrefixConfig,
+    ParserConfig, ResponseEncoding,
+};
+use crate::parse::{self, ParseResponseError};
 
-// Encoded command contains: command + 2 bytes crc + \r
-// Maximum 8 bytes
-pub const MAX_COMMAND_LENGTH: usize = 5;
+// Encoded command contains: command + argument + 2 bytes crc + \r
+// padded to a multiple of the pad length, maximum 2 reports
+pub const REPORT_SIZE: usize = 8;
+pub const MAX_COMMAND_LENGTH: usize = 2 * REPORT_SIZE - 3;
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 256;
+pub const DEFAULT_PAD_BYTE: u8 = b'\0';
+pub const DEFAULT_PAD_LENGTH: usize = REPORT_SIZE;
 const START_RESPONSE_MARKER: u8 = b'(';
-const END_RESPONSE_MARKER: u8 = b'\r';
+// Maximum number of junk bytes skipped before the start marker
+const MAX_DISCARDED_BYTES: usize = 64;
+pub const END_RESPONSE_MARKER: u8 = b'\r';
+const ACK_RESPONSE: &str = "ACK";
+const NAK_RESPONSE: &str = "NAK";
+pub static DEFAULT_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+// Also known as CRC-16/CCITT-FALSE
+static CCITT_FALSE_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+static MODBUS_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
 
-#[derive(Debug, PartialEq, Snafu)]
+#[derive(Debug, Snafu)]
 pub enum DeviceError {
     #[snafu(display("USB device error: {source}"))]
     Usb { source: UsbError },
+
+    #[snafu(display("I/O device error: {source}"))]
+    Io { source: IoError },
+}
+
+impl DeviceError {
+    // Sockets with a read timeout fail with WouldBlock
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::Usb { source } => *source == UsbError::Timeout,
+            Self::Io { source } => {
+                matches!(source.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+            }
+        }
+    }
+}
+
+impl PartialEq for DeviceError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Usb { source }, Self::Usb { source: other_source }) => source == other_source,
+            (Self::Io { source }, Self::Io { source: other_source }) => {
+                source.kind() == other_source.kind()
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Snafu)]
@@ -32,226 +80,687 @@ pub enum InverterError {
     #[snafu(display("Command too long: {cmd}"))]
     CommandTooLong { cmd: String },
 
+    #[snafu(display("Response exceeds {max} bytes"))]
+    ResponseTooLong { max: usize },
+
     #[snafu(display("Missing response marker"))]
     MissingResponseMarker,
 
+    #[snafu(display("Response of {len} bytes is too short to contain crc"))]
+    ResponseTooShort { len: usize },
+
     #[snafu(display("Expected UTF-8: {source}"))]
     ExpectedUtf8 { source: Utf8Error },
 
     #[snafu(display("Parse response error: {source}"))]
     ParseResponse { source: ParseResponseError },
 
+    #[snafu(display("Response is not from the unit with address {address}: '{data}'"))]
+    AddressMismatch { address: String, data: String },
+
     #[snafu(display("Invalid crc, expected {expected} but was {actual}: '{data}'"))]
     InvalidCrc { expected: String, actual: String, data: String },
-}
 
-#[derive(Debug, PartialEq, Snafu)]
-pub enum ParseResponseError {
-    #[snafu(display("Expected float value for '{sensor}' sensor: {source}"))]
-    ExpectedFloat { sensor: String, source: ParseFloatError },
+    #[snafu(display("Inverter rejected '{cmd}' command"))]
+    CommandRejected { cmd: String },
 
-    #[snafu(display("Expected integer value for '{sensor}' sensor: {source}"))]
-    ExpectedInteger { sensor: String, source: ParseIntError },
+    #[snafu(display("Unexpected reply to '{cmd}' command: '{data}'"))]
+    UnexpectedReply { cmd: String, data: String },
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
 pub enum SensorValue {
     Integer(i64),
     Float(f64),
     String(String),
+    Boolean(bool),
+    // Field is present but its value cannot be interpreted
+    Unavailable,
 }
 
 pub trait InverterDevice {
     fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError>;
+    // Returns the number of bytes read, the rest of the buffer is ignored
     fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError>;
+
+    // Reads are never smaller than a single packet
+    fn packet_size(&self) -> usize {
+        REPORT_SIZE
+    }
+
+    // Closes the device until the next request, so other tools can use it in between
+    fn release(&mut self) {}
+
+    // Line speed of serial devices
+    fn baud_rate(&self) -> Option<u32> {
+        None
+    }
+
+    // Switches to the next candidate line speed, none if the device has no candidates
+    fn next_baud_rate(&mut self) -> Option<u32> {
+        None
+    }
 }
 
 pub struct Inverter<T: InverterDevice> {
     dev: T,
+    protocol: Protocol,
+    last_frame: Vec<u8>,
+    last_sent_at: Option<Instant>,
 }
 
-impl<T: InverterDevice> Inverter<T> {
+// Device independent part of the protocol: encoding commands and decoding responses
+struct Protocol {
+    max_response_bytes: usize,
+    decimal_comma: bool,
+    pad_byte: u8,
+    pad_length: usize,
+    address: Option<String>,
+    framing: FramingConfig,
+    crc: &'static Crc<u16>,
+    min_command_interval: Duration,
+}
+
+pub struct InverterBuilder<T> {
+    dev: T,
+    protocol: Protocol,
+}
+
+impl<T> InverterBuilder<T> {
     pub fn new(dev: T) -> Self {
         Self {
             dev,
+            protocol: Protocol {
+                max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+                decimal_comma: false,
+                pad_byte: DEFAULT_PAD_BYTE,
+                pad_length: DEFAULT_PAD_LENGTH,
+                address: None,
+                framing: FramingConfig::EndMarker,
+                crc: &DEFAULT_CRC,
+                min_command_interval: Duration::ZERO,
+            },
         }
     }
 
-    fn calc_crc(&self, data: &[u8]) -> u16 {
-        let crc = Crc::<u16>::new(&CRC_16_XMODEM);
-        let mut digest = crc.digest();
-        digest.update(data);
-        digest.finalize()
+    pub fn max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.protocol.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    pub fn decimal_comma(mut self, decimal_comma: bool) -> Self {
+        self.protocol.decimal_comma = decimal_comma;
+        self
+    }
+
+    // Pads command frames to a multiple of the length, zero disables padding
+    pub fn padding(mut self, pad_byte: u8, pad_length: usize) -> Self {
+        self.protocol.pad_byte = pad_byte;
+        self.protocol.pad_length = pad_length;
+        self
+    }
+
+    // Default address prepended to commands for units on a shared bus
+    pub fn address(mut self, address: Option<String>) -> Self {
+        self.protocol.address = address;
+        self
+    }
+
+    pub fn framing(mut self, framing: FramingConfig) -> Self {
+        self.protocol.framing = framing;
+        self
+    }
+
+    pub fn crc(mut self, crc: &'static Crc<u16>) -> Self {
+        self.protocol.crc = crc;
+        self
+    }
+
+    // Protects fragile inverters from commands sent too rapidly
+    pub fn min_command_interval(mut self, interval: Duration) -> Self {
+        self.protocol.min_command_interval = interval;
+        self
     }
 
+    pub fn build(self) -> Inverter<T> where T: InverterDevice {
+        Inverter {
+            dev: self.dev,
+            protocol: self.protocol,
+            last_frame: vec!(),
+            last_sent_at: None,
+        }
+    }
+}
+
+impl Protocol {
     fn encode_command(&self, cmd: &str) -> Result<Vec<u8>, InverterError> {
+        log::trace!("Sending command to inverter: {cmd}");
         let mut res = vec!();
         res.extend(cmd.bytes());
         if res.len() > MAX_COMMAND_LENGTH {
             return Err(InverterError::CommandTooLong { cmd: cmd.to_string() });
         }
-        let crc = self.calc_crc(&res);
+        let crc = calc_crc(self.crc, &res);
 
         res.push((crc >> 8) as u8);
         res.push((crc & 0xff) as u8);
         res.push(b'\r');
-        if res.len() < 8 {
-            res.resize(8, b'\0');
+        if self.pad_length > 0 {
+            let padded_len = res.len().div_ceil(self.pad_length) * self.pad_length;
+            res.resize(padded_len, self.pad_byte);
         }
         Ok(res)
     }
 
-    fn send_command(&mut self, cmd: &str) -> Result<usize, InverterError> {
-        log::trace!("Sending command to inverter: {cmd}");
-        let cmd = self.encode_command(cmd)?;
-        self.dev.send_request(&cmd)
-            .context(DeviceSnafu)
+    fn frame_reader(&self) -> FrameReader {
+        FrameReader {
+            resp: vec!(),
+            discarded: 0,
+            scanned: 0,
+            end_candidate: None,
+            max_response_bytes: self.max_response_bytes,
+            framing: self.framing.clone(),
+            crc: self.crc,
+        }
     }
 
-    fn read_response(&mut self) -> Result<String, InverterError> {
-        let mut resp = Vec::<u8>::new();
-        loop {
-            let mut buf = [0; 8];
-            self.dev.read_response(&mut buf)
-                .context(DeviceSnafu)?;
-            let chunk = slice_trim_end_matches(&buf, |&b| b == b'\0');
-            resp.extend(chunk);
-            if let Some(&END_RESPONSE_MARKER) = chunk.last() {
-                resp.pop();
-                break;
+    fn command_address<'a>(&'a self, cfg: &'a CommandConfig) -> Option<&'a str> {
+        cfg.address.as_deref().or(self.address.as_deref())
+    }
+
+    // Command prefixed with the unit address
+    fn full_command(&self, cfg: &CommandConfig) -> String {
+        format!("{}{}", self.command_address(cfg).unwrap_or(""), cfg.full_command())
+    }
+
+    fn strip_address<'a>(
+        &self,
+        cfg: &CommandConfig,
+        resp: &'a [u8],
+    ) -> Result<&'a [u8], InverterError> {
+        strip_unit_address(self.command_address(cfg), resp)
+    }
+
+    // Header is the first frame of responses to commands with the header parser
+    fn decode_response(
+        &self,
+        cfg: &CommandConfig,
+        header: Option<&[u8]>,
+        resp: &[u8],
+    ) -> Result<HashMap<String, SensorValue>, InverterError> {
+        let resp = self.strip_address(cfg, resp)?;
+        let mut sensors_data = match &cfg.parser {
+            ParserConfig::Whitespace => {
+                let resp = decode_text(cfg.encoding, resp)?;
+                parse::parse_whitespace(cfg, &resp, self.decimal_comma)
+            }
+            ParserConfig::FixedWidth(fixed_width) => {
+                let resp = decode_text(cfg.encoding, resp)?;
+                parse::parse_fixed_width(cfg, fixed_width, &resp, self.decimal_comma)
+            }
+            ParserConfig::Binary => parse::parse_binary(cfg, resp),
+            ParserConfig::Header => {
+                let header = self.strip_address(cfg, header.unwrap_or_default())?;
+                let header = decode_text(cfg.encoding, header)?;
+                let resp = decode_text(cfg.encoding, resp)?;
+                parse::parse_header(cfg, &header, &resp, self.decimal_comma)
+            }
+        }
+            .context(ParseResponseSnafu)?;
+        for sensor in cfg.sensors.iter().flatten() {
+            if let Some(value) = sensors_data.remove(&sensor.name) {
+                sensors_data.insert(sensor.name.clone(), parse::scale_value(sensor, value));
+            }
+        }
+        for sensor in cfg.sensors.iter().flatten().filter(|s| !s.bits.is_empty()) {
+            if let Some(SensorValue::String(flags)) = sensors_data.get(&sensor.name) {
+                let bits = expand_bits(&sensor.bits, flags);
+                sensors_data.extend(bits);
             }
         }
+        Ok(sensors_data)
+    }
+}
+
+// Units on a shared bus echo their address at the start of the response data
+fn strip_unit_address<'a>(
+    address: Option<&str>,
+    resp: &'a [u8],
+) -> Result<&'a [u8], InverterError> {
+    match address {
+        Some(address) => match resp.strip_prefix(address.as_bytes()) {
+            Some(data) => Ok(data),
+            None => Err(InverterError::AddressMismatch {
+                address: address.to_string(),
+                data: String::from_utf8_lossy(resp).into_owned(),
+            }),
+        },
+        None => Ok(resp),
+    }
+}
 
-        if resp[0] != START_RESPONSE_MARKER {
-            return Err(InverterError::MissingResponseMarker);
+fn decode_text(encoding: ResponseEncoding, resp: &[u8]) -> Result<Cow<'_, str>, InverterError> {
+    match encoding {
+        ResponseEncoding::Utf8 | ResponseEncoding::Binary => {
+            str::from_utf8(resp).map(Cow::Borrowed).context(ExpectedUtf8Snafu)
         }
+        // Every byte is the code point of the same value
+        ResponseEncoding::Latin1 => Ok(resp.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+// Every character of a status flags field is a separate bit which is set by '1'
+fn expand_bits(bits: &[Option<BitConfig>], flags: &str) -> Vec<(String, SensorValue)> {
+    bits.iter()
+        .zip(flags.chars())
+        .filter_map(|(bit, flag)| {
+            let value = match flag {
+                '1' => SensorValue::Boolean(true),
+                '0' => SensorValue::Boolean(false),
+                _ => SensorValue::Unavailable,
+            };
+            Some((bit.as_ref()?.name.clone(), value))
+        })
+        .collect()
+}
+
+// Accumulates chunks read from a device into a response frame
+struct FrameReader {
+    resp: Vec<u8>,
+    discarded: usize,
+    // Length of the response up to which end markers were checked
+    scanned: usize,
+    // First end marker with a mismatched crc, it can be a crc byte instead
+    end_candidate: Option<usize>,
+    max_response_bytes: usize,
+    framing: FramingConfig,
+    crc: &'static Crc<u16>,
+}
 
-        if self.calc_crc(&resp) != 0 {
-            let data_for_crc = &resp[..resp.len()-2];
-            let actual_crc = ((resp[resp.len()-2] as u16) << 8) | resp[resp.len()-1] as u16;
-            return Err(InverterError::InvalidCrc {
-                expected: format!("{:#06x}", self.calc_crc(data_for_crc)),
-                actual: format!("{actual_crc:#06x}"),
-                data: String::from_utf8_lossy(data_for_crc).into_owned(),
-            });
+impl FrameReader {
+    // Returns true when the frame is complete
+    fn push(&mut self, chunk: &[u8]) -> Result<bool, InverterError> {
+        if let FramingConfig::LengthPrefixed(prefix) = &self.framing {
+            return push_length_prefixed(&mut self.resp, prefix, chunk, self.max_response_bytes);
+        }
+        if chunk.is_empty() {
+            // Nothing more to read so the frame ends at the end marker seen before
+            return Ok(self.end_candidate.is_some());
+        }
+        let resp = &mut self.resp;
+        resp.extend(chunk);
+        // Resync on the start marker dropping leftovers of a previous response
+        if resp.first().is_some_and(|&b| b != START_RESPONSE_MARKER) {
+            let junk_len = resp.iter()
+                .position(|&b| b == START_RESPONSE_MARKER)
+                .unwrap_or(resp.len());
+            // Zero padding of packets is not junk
+            let junk = &resp[..junk_len];
+            if junk.iter().any(|&b| b != b'\0') {
+                self.discarded += junk.len();
+                if self.discarded > MAX_DISCARDED_BYTES {
+                    return Err(InverterError::MissingResponseMarker);
+                }
+                log::debug!("Discarding bytes before response marker: {junk:?}");
+            }
+            resp.drain(..junk_len);
+        }
+        // Anything after the end marker is padding
+        while let Some(pos) = resp[self.scanned..].iter().position(|&b| b == END_RESPONSE_MARKER) {
+            let end = self.scanned + pos;
+            self.scanned = end + 1;
+            if crc_matches(self.crc, &resp[..end]) {
+                resp.truncate(end);
+                self.end_candidate = None;
+                return Ok(true);
+            }
+            self.end_candidate.get_or_insert(end);
         }
+        // A marker that is a crc byte is followed by the real one at most two bytes later
+        if self.end_candidate.is_some_and(|end| resp.len() > end + 2) {
+            return Ok(true);
+        }
+        if resp.len() > self.max_response_bytes {
+            return Err(InverterError::ResponseTooLong { max: self.max_response_bytes });
+        }
+        Ok(false)
+    }
+
+    fn raw_frame(&self) -> &[u8] {
+        &self.resp[..self.end_candidate.unwrap_or(self.resp.len())]
+    }
+
+    // Timeouts after a possible end marker complete the frame
+    fn may_be_complete(&self) -> bool {
+        self.end_candidate.is_some()
+    }
 
-        let resp = str::from_utf8(&resp[1..resp.len()-2])
+    // Returns CRC-checked data between the markers
+    fn finish(mut self) -> Result<Vec<u8>, InverterError> {
+        if let Some(end) = self.end_candidate {
+            self.resp.truncate(end);
+        }
+        let frame = match &self.framing {
+            FramingConfig::EndMarker => &self.resp[..],
+            FramingConfig::LengthPrefixed(prefix) => {
+                let frame = &self.resp[prefix.prefix_size..];
+                frame.strip_suffix(&[END_RESPONSE_MARKER]).unwrap_or(frame)
+            }
+        };
+        let data = check_frame(self.crc, frame)?;
+        log::trace!("Read inverter response: {}", String::from_utf8_lossy(data));
+        Ok(data.to_vec())
+    }
+}
+
+fn read_into<T: InverterDevice>(dev: &mut T, reader: &mut FrameReader) -> Result<(), InverterError> {
+    let mut buf = vec![0; dev.packet_size().max(REPORT_SIZE)];
+    loop {
+        let n = match dev.read_response(&mut buf) {
+            Err(e) if e.is_timeout() && reader.may_be_complete() => return Ok(()),
+            res => res.context(DeviceSnafu)?,
+        };
+        if reader.push(&buf[..n])? {
+            return Ok(());
+        }
+    }
+}
+
+// Reads exactly the number of bytes given by the prefix, padding after them is dropped
+fn push_length_prefixed(
+    resp: &mut Vec<u8>,
+    prefix: &LengthPrefixConfig,
+    chunk: &[u8],
+    max_response_bytes: usize,
+) -> Result<bool, InverterError> {
+    resp.extend(chunk);
+    if resp.len() < prefix.prefix_size {
+        return Ok(false);
+    }
+    let frame_len = decode_length(&resp[..prefix.prefix_size], prefix.endian);
+    if frame_len > max_response_bytes {
+        return Err(InverterError::ResponseTooLong { max: max_response_bytes });
+    }
+    let total_len = prefix.prefix_size + frame_len;
+    if resp.len() < total_len {
+        return Ok(false);
+    }
+    resp.truncate(total_len);
+    Ok(true)
+}
+
+fn decode_length(bytes: &[u8], endian: Endian) -> usize {
+    let fold = |len: usize, &b: &u8| (len << 8) | b as usize;
+    match endian {
+        Endian::Big => bytes.iter().fold(0, fold),
+        Endian::Little => bytes.iter().rev().fold(0, fold),
+    }
+}
+
+impl<T: InverterDevice> Inverter<T> {
+    pub fn builder(dev: T) -> InverterBuilder<T> {
+        InverterBuilder::new(dev)
+    }
+
+    fn send_command(&mut self, cmd: &str) -> Result<usize, InverterError> {
+        let cmd = self.protocol.encode_command(cmd)?;
+        if let Some(last_sent_at) = self.last_sent_at {
+            let elapsed = last_sent_at.elapsed();
+            if elapsed < self.protocol.min_command_interval {
+                sleep(self.protocol.min_command_interval - elapsed);
+            }
+        }
+        self.last_sent_at = Some(Instant::now());
+        self.dev.send_request(&cmd)
+            .context(DeviceSnafu)
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>, InverterError> {
+        let mut reader = self.protocol.frame_reader();
+        let res = read_into(&mut self.dev, &mut reader);
+        // Kept for debugging even when the frame is broken
+        self.last_frame = reader.raw_frame().to_vec();
+        res?;
+        reader.finish()
+    }
+
+    // Keeps the device open when the inverter is rebuilt with new protocol settings
+    pub fn into_device(self) -> T {
+        self.dev
+    }
+
+    // Raw bytes of the last response from the start marker to crc, empty if nothing was read
+    pub fn last_frame(&self) -> &[u8] {
+        &self.last_frame
+    }
+
+    pub fn release_device(&mut self) {
+        self.dev.release();
+    }
+
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.dev.baud_rate()
+    }
+
+    pub fn next_baud_rate(&mut self) -> Option<u32> {
+        self.dev.next_baud_rate()
+    }
+
+    // Raw command addressed like configured commands, e.g. the startup probe
+    pub fn query_unit(&mut self, cmd: &str) -> Result<String, InverterError> {
+        let address = self.protocol.address.clone();
+        let resp = self.query_frame(&format!("{}{cmd}", address.as_deref().unwrap_or("")))?;
+        let resp = strip_unit_address(address.as_deref(), &resp)?;
+        let resp = str::from_utf8(resp)
             .context(ExpectedUtf8Snafu)?;
-        log::trace!("Read inverter response: {resp}");
         Ok(resp.to_string())
     }
 
+    // Sends a raw command and returns CRC-checked response data
+    pub fn query(&mut self, cmd: &str) -> Result<String, InverterError> {
+        let resp = self.query_frame(cmd)?;
+        let resp = str::from_utf8(&resp)
+            .context(ExpectedUtf8Snafu)?;
+        Ok(resp.to_string())
+    }
+
+    // Sends a setting command, e.g. POP02, that is acknowledged with ACK or rejected with NAK
+    pub fn write_command(&mut self, cmd: &str) -> Result<(), InverterError> {
+        match self.query(cmd)?.as_str() {
+            ACK_RESPONSE => Ok(()),
+            NAK_RESPONSE => CommandRejectedSnafu { cmd }.fail(),
+            data => UnexpectedReplySnafu { cmd, data }.fail(),
+        }
+    }
+
+    fn query_frame(&mut self, cmd: &str) -> Result<Vec<u8>, InverterError> {
+        self.last_frame.clear();
+        self.send_command(cmd)?;
+        self.read_frame()
+    }
+
     pub fn execute_command(
         &mut self,
         cfg: &CommandConfig
     ) -> Result<HashMap<String, SensorValue>, InverterError> {
-        self.send_command(&cfg.command)?;
-        let resp = self.read_response()?;
-        let mut sensors_data = HashMap::new();
-        for (sensor, value) in cfg.sensors.iter().zip(resp.split_ascii_whitespace()) {
-            if let Some(sensor) = sensor {
-                let value = match sensor.value_type {
-                    ValueType::Integer => SensorValue::Integer(
-                        value.parse::<i64>()
-                            .context(ExpectedIntegerSnafu { sensor: sensor.name.clone() })
-                            .context(ParseResponseSnafu)?
-                    ),
-                    ValueType::Float => SensorValue::Float(
-                        value.parse::<f64>()
-                            .context(ExpectedFloatSnafu { sensor: sensor.name.clone() })
-                            .context(ParseResponseSnafu)?
-                    ),
-                    ValueType::String => SensorValue::String(
-                        value.to_string()
-                    ),
-                };
-                sensors_data.insert(sensor.name.clone(), value);
-            }
-        }
-        Ok(sensors_data)
+        let mut resp = self.query_frame(&self.protocol.full_command(cfg))?;
+        let header = if cfg.parser == ParserConfig::Header {
+            Some(std::mem::replace(&mut resp, self.read_frame()?))
+        } else {
+            None
+        };
+        self.protocol.decode_response(cfg, header.as_deref(), &resp)
+    }
+}
+
+pub fn calc_crc(crc: &Crc<u16>, data: &[u8]) -> u16 {
+    let mut digest = crc.digest();
+    digest.update(data);
+    digest.finalize()
+}
+
+// The crc crate needs a static algorithm, it is built once at startup
+pub fn catalog_crc(algorithm: CrcAlgorithm) -> &'static Crc<u16> {
+    match algorithm {
+        CrcAlgorithm::Xmodem => &DEFAULT_CRC,
+        CrcAlgorithm::CcittFalse => &CCITT_FALSE_CRC,
+        CrcAlgorithm::Modbus => &MODBUS_CRC,
     }
 }
 
-fn slice_trim_end_matches<T, F: Fn(&T) -> bool>(arr: &[T], f: F) -> &[T] {
-    let mut res = arr;
-    while res.len() > 0 && f(&res[res.len()-1]) {
-        res = &res[..(res.len()-1)];
+// Leaked once per distinct algorithm, so reloads and inverters sharing it add nothing
+pub fn custom_crc(cfg: &CrcConfig) -> &'static Crc<u16> {
+    static CUSTOM_CRCS: OnceLock<Mutex<HashMap<CrcConfig, &'static Crc<u16>>>> = OnceLock::new();
+    let mut crcs = CUSTOM_CRCS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    crcs.entry(cfg.clone()).or_insert_with(|| {
+        let algorithm = Box::leak(Box::new(Algorithm {
+            width: 16,
+            poly: cfg.poly,
+            init: cfg.init,
+            refin: cfg.refin,
+            refout: cfg.refout,
+            xorout: cfg.xorout,
+            // Only used for self-checks of the catalog algorithms
+            check: 0,
+            residue: 0,
+        }));
+        Box::leak(Box::new(Crc::<u16>::new(algorithm)))
+    })
+}
+
+// Crc is sent big endian after the data
+fn crc_matches(crc: &Crc<u16>, frame: &[u8]) -> bool {
+    match frame.len().checked_sub(2) {
+        Some(len) => calc_crc(crc, &frame[..len]).to_be_bytes() == frame[len..],
+        None => false,
     }
-    res
+}
+
+// Checks a frame without the end marker and returns data between the start marker and crc
+pub fn check_frame<'a>(crc: &Crc<u16>, frame: &'a [u8]) -> Result<&'a [u8], InverterError> {
+    if frame.first() != Some(&START_RESPONSE_MARKER) {
+        return Err(InverterError::MissingResponseMarker);
+    }
+    if frame.len() < 3 {
+        return Err(InverterError::ResponseTooShort { len: frame.len() });
+    }
+    let (data_for_crc, frame_crc) = frame.split_at(frame.len() - 2);
+    if !crc_matches(crc, frame) {
+        let expected_crc = calc_crc(crc, data_for_crc);
+        let actual_crc = ((frame_crc[0] as u16) << 8) | frame_crc[1] as u16;
+        return Err(InverterError::InvalidCrc {
+            expected: format!("{expected_crc:#06x}"),
+            actual: format!("{actual_crc:#06x}"),
+            data: String::from_utf8_lossy(data_for_crc).into_owned(),
+        });
+    }
+    Ok(&data_for_crc[1..])
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::time::{Duration, Instant};
 
-    use crate::config::{CommandConfig, SensorConfig, ValueType};
+    use crate::config::{
+        BinaryField,
+        CommandConfig,
+        CrcAlgorithm,
+        CrcConfig,
+        Endian,
+        FieldDecoder,
+        FramingConfig,
+        LengthPrefixConfig,
+        ParserConfig,
+        ResponseEncoding,
+        SensorConfig,
+        ShortResponsePolicy,
+        ValueType,
+    };
+    use crate::parse::ParseResponseError;
+    use crate::testutil::MockDevice;
     use super::{
+        calc_crc,
+        catalog_crc,
+        check_frame,
+        custom_crc,
         DeviceError,
+        DEFAULT_CRC,
         Inverter,
-        InverterDevice,
         InverterError,
-        ParseResponseError,
         SensorValue,
     };
 
-    const ENCODED_STATUS_CMD: &'static [u8] = &[81, 80, 73, 71, 83, 183, 169, 13];
-
-    struct TestInverterDevice<'req, 'resp> {
-        expected_request: &'req [u8],
-        response: &'resp [u8],
-    }
+    const ENCODED_STATUS_CMD: &[u8] = &[81, 80, 73, 71, 83, 183, 169, 13];
 
-    impl<'req, 'resp> TestInverterDevice<'req, 'resp> {
-        fn new(expected_request: &'req [u8], response: &'resp [u8]) -> Self {
-            Self {
-                expected_request,
-                response,
-            }
+    fn command_config(command: &str, sensors: Vec<Option<SensorConfig>>) -> CommandConfig {
+        CommandConfig {
+            command: command.to_string(),
+            argument: None,
+            address: None,
+            interval_secs: None,
+            skip_leading_fields: 0,
+            trailing_fields_to_drop: 0,
+            parser: ParserConfig::Whitespace,
+            encoding: ResponseEncoding::Utf8,
+            on_short_response: ShortResponsePolicy::Warn,
+            sensors,
+            capture_extra: None,
+            section_separator: None,
+            section_sizes: vec!(),
         }
     }
 
-    impl<'req, 'resp> InverterDevice for TestInverterDevice<'req, 'resp> {
-        fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
-            assert_eq!(buf, self.expected_request);
-            Ok(0)
-        }
-
-        fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
-            let (cur_resp, rest) = self.response.split_at(buf.len());
-            buf.copy_from_slice(cur_resp);
-            self.response = rest;
-            Ok(buf.len())
+    fn sensor_config(name: &str, value_type: ValueType) -> SensorConfig {
+        SensorConfig {
+            name: name.to_string(),
+            human_name: None,
+            object_id: None,
+            unique_id: None,
+            value_type,
+            device_class: "voltage".to_string(),
+            unit_of_measurement: "V".to_string(),
+            icon: "mdi:power-plug".to_string(),
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            available_when: None,
+            binary_field: None,
+            trim: false,
+            strip_leading_zeros: false,
+            extra_state_topics: vec!(),
+            fallback: None,
+            radix: None,
+            scale: None,
+            offset: None,
+            true_value: None,
+            false_value: None,
+            datetime_format: None,
+            utc_offset: None,
+            device: None,
+            bits: vec!(),
+            aggregate: None,
+            map: HashMap::new(),
         }
     }
 
     #[test]
     fn test_inverter_execute_command() {
-        let mut inverter = Inverter::new(
-            TestInverterDevice::new(
-                ENCODED_STATUS_CMD,
-                &[
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
                     b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7',
                     0x09, 0xc7, 13, 0, 0, 0, 0, 0,
-                ]
-            )
-        );
-        let command_config = CommandConfig {
-            command: "QPIGS".to_string(),
-            sensors: vec!(
-                None,
-                Some(
-                    SensorConfig {
-                        name: "sensor1".to_string(),
-                        human_name: None,
-                        value_type: ValueType::Float,
-                        device_class: "voltage".to_string(),
-                        unit_of_measurement: "V".to_string(),
-                        icon: "mdi:power-plug".to_string(),
-                    }
-                )
-            ),
-        };
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            None,
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
         let mut expected_result = HashMap::new();
         expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
         assert_eq!(
@@ -262,19 +771,15 @@ mod tests {
 
     #[test]
     fn test_inverter_execute_command_invalid_crc() {
-        let mut inverter = Inverter::new(
-            TestInverterDevice::new(
-                ENCODED_STATUS_CMD,
-                &[
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
                     b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7',
                     0x09, 0xc8, 13, 0, 0, 0, 0, 0,
-                ]
-            )
-        );
-        let command_config = CommandConfig {
-            command: "QPIGS".to_string(),
-            sensors: vec!(None)
-        };
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(None));
         assert_eq!(
             inverter.execute_command(&command_config).unwrap_err(),
             InverterError::InvalidCrc {
@@ -285,31 +790,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_inverter_execute_command_invalid_crc_before_padding() {
+        let invalid_crc = InverterError::InvalidCrc {
+            expected: "0x09c7".to_string(),
+            actual: "0x09c8".to_string(),
+            data: "(0 233.7".to_string(),
+        };
+        let frame = [b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7', 0x09, 0xc8, 13];
+        let command_config = command_config("QPIGS", vec!(None));
+        // Frame ends once no crc byte can be left before the end marker
+        let mut inverter = Inverter::builder(MockDevice::new().respond(&frame).respond_endless(0))
+            .build();
+        assert_eq!(inverter.execute_command(&command_config).unwrap_err(), invalid_crc);
+
+        // Only timeouts end the frame, other errors are not hidden
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&frame)
+                .pass_read()
+                .pass_read()
+                .fail_read(DeviceError::Usb { source: rusb::Error::NoDevice })
+        ).build();
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::Device { source: DeviceError::Usb { source: rusb::Error::NoDevice } }
+        );
+    }
+
     #[test]
     fn test_inverter_execute_command_invalid_value() {
-        let mut inverter = Inverter::new(
-            TestInverterDevice::new(
-                ENCODED_STATUS_CMD,
-                &[
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
                     b'(', b'a', 0xf3, 0xc8, 13, 0, 0, 0,
-                ]
-            )
-        );
-        let command_config = CommandConfig {
-            command: "QPIGS".to_string(),
-            sensors: vec!(
-                Some(
-                    SensorConfig {
-                        name: "sensor1".to_string(),
-                        human_name: None,
-                        value_type: ValueType::Float,
-                        device_class: "voltage".to_string(),
-                        unit_of_measurement: "V".to_string(),
-                        icon: "mdi:power-plug".to_string(),
-                    }
-                )
-            ),
-        };
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
         assert_eq!(
             inverter.execute_command(&command_config).unwrap_err(),
             InverterError::ParseResponse {
@@ -320,4 +840,836 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_inverter_execute_command_ping() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'A', b'C', b'K', 0x39, 0x20, 13, 0])
+                .respond(&[b'(', b'A', b'C', b'K', 0x39, 0x20, 13, 0])
+        ).build();
+        for sensors in [vec!(None, None), vec!()] {
+            let command_config = command_config("QPIGS", sensors);
+            assert!(command_config.is_ping());
+            assert_eq!(inverter.execute_command(&command_config).unwrap(), HashMap::new());
+        }
+    }
+
+    #[test]
+    fn test_inverter_execute_command_response_too_long() {
+        let mut inverter = Inverter::builder(MockDevice::new().respond(b"(").respond_endless(b'0'))
+            .max_response_bytes(16)
+            .build();
+        let command_config = command_config("QPIGS", vec!(None));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::ResponseTooLong { max: 16 }
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_with_argument() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[
+                    b'P', b'C', b'V', b'V', b'5', b'6', b'.', b'4',
+                    0x5f, 0x5f, 13, 0, 0, 0, 0, 0,
+                ])
+                .respond(&[
+                    b'(', b'A', b'C', b'K', 0x39, 0x20, 13, 0,
+                ])
+        ).build();
+        let mut command_config = command_config("PCVV", vec!());
+        command_config.argument = Some("56.4".to_string());
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            HashMap::new()
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_sections() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(b"(230.0 50.0|12.5 1")
+                .respond(&[0x57, 0x1e, 13, 0, 0, 0])
+        ).build();
+        let mut command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("grid_voltage", ValueType::Float)),
+            None,
+            Some(sensor_config("battery_voltage", ValueType::Float)),
+            Some(sensor_config("charging", ValueType::Integer)),
+        ));
+        command_config.section_separator = Some("|".to_string());
+        command_config.section_sizes = vec!(2, 2);
+        let mut expected_result = HashMap::new();
+        expected_result.insert("grid_voltage".to_string(), SensorValue::Float(230.0));
+        expected_result.insert("battery_voltage".to_string(), SensorValue::Float(12.5));
+        expected_result.insert("charging".to_string(), SensorValue::Integer(1));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_decimal_comma() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'0', b' ', b'2', b'3', b'3', b',', b'7',
+                    0x6f, 0xa5, 13, 0, 0, 0, 0, 0,
+                ])
+        )
+            .decimal_comma(true)
+            .build();
+        let command_config = command_config("QPIGS", vec!(
+            None,
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_device_error() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .timeout_read()
+        ).build();
+        let command_config = command_config("QPIGS", vec!(None));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::Device {
+                source: DeviceError::Usb { source: rusb::Error::Timeout }
+            }
+        );
+    }
+
+    #[test]
+    fn test_inverter_min_command_interval() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+        )
+            .min_command_interval(Duration::from_millis(50))
+            .build();
+        let started_at = Instant::now();
+        inverter.query("QID").unwrap();
+        assert!(started_at.elapsed() < Duration::from_millis(50));
+        inverter.query("QID").unwrap();
+        inverter.query("QID").unwrap();
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_inverter_last_frame() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1d, 13, 0, 0, 0])
+                .pass_send()
+                .pass_send()
+                .fail_send(DeviceError::Usb { source: rusb::Error::NoDevice })
+        ).build();
+        inverter.query("QID").unwrap();
+        assert_eq!(inverter.last_frame(), &[b'(', b'0', 0xb9, 0x1c]);
+        inverter.query("QID").unwrap_err();
+        assert_eq!(inverter.last_frame(), &[b'(', b'0', 0xb9, 0x1d]);
+        inverter.query("QID").unwrap_err();
+        assert!(inverter.last_frame().is_empty());
+    }
+
+    #[test]
+    fn test_inverter_query_recovers_after_timeout() {
+        let frame = [b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7', 0x09, 0xc7, 13];
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .read_limit(4)
+                .pass_read()
+                .timeout_read()
+                .respond(&frame)
+                .respond(&frame)
+        ).build();
+        assert_eq!(
+            inverter.query("QPIGS").unwrap_err(),
+            InverterError::Device { source: DeviceError::Usb { source: rusb::Error::Timeout } }
+        );
+        // Late tail of the timed out response is skipped as junk
+        assert_eq!(inverter.query("QPIGS").unwrap(), "0 233.7");
+    }
+
+    #[test]
+    fn test_inverter_write_command() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'A', b'C', b'K', 0x39, 0x20, 13, 0])
+                .respond(&[b'(', b'N', b'A', b'K', 0x73, 0x73, 13, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+        ).build();
+        assert_eq!(inverter.write_command("POP02"), Ok(()));
+        assert_eq!(
+            inverter.write_command("POP05").unwrap_err(),
+            InverterError::CommandRejected { cmd: "POP05".to_string() }
+        );
+        assert_eq!(
+            inverter.write_command("POP02").unwrap_err(),
+            InverterError::UnexpectedReply { cmd: "POP02".to_string(), data: "0".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_send_error() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .fail_send(DeviceError::Usb { source: rusb::Error::NoDevice })
+        ).build();
+        let command_config = command_config("QPIGS", vec!(None));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::Device {
+                source: DeviceError::Usb { source: rusb::Error::NoDevice }
+            }
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_leading_junk() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'1', b'2', 13, b'(', b'0', b' ', b'2', b'3',
+                    b'3', b'.', b'7', 0x09, 0xc7, 13, 0, 0,
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            None,
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_missing_response_marker() {
+        let mut inverter = Inverter::builder(MockDevice::new().respond_endless(b'x')).build();
+        let command_config = command_config("QPIGS", vec!(None));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::MissingResponseMarker
+        );
+    }
+
+    #[test]
+    fn test_inverter_encode_command_no_padding() {
+        let inverter = Inverter::builder(MockDevice::new())
+            .padding(b'\0', 0)
+            .build();
+        assert_eq!(
+            inverter.protocol.encode_command("QID").unwrap(),
+            vec!(b'Q', b'I', b'D', 0xd6, 0xea, 13)
+        );
+    }
+
+    #[test]
+    fn test_inverter_encode_command_pad_byte() {
+        let inverter = Inverter::builder(MockDevice::new())
+            .padding(b' ', 8)
+            .build();
+        assert_eq!(
+            inverter.protocol.encode_command("QID").unwrap(),
+            vec!(b'Q', b'I', b'D', 0xd6, 0xea, 13, b' ', b' ')
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_skip_leading_fields() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'2', b' ', b'5', b' ', b'2', b'3', b'3',
+                    b'.', b'7', 0x2f, 0x99, 13, 0, 0, 0,
+                ])
+        ).build();
+        let mut command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        command_config.skip_leading_fields = 2;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_trailing_fields_to_drop() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'2', b'3', b'3', b'.', b'7', b' ', b'B',
+                    0xe4, 0xcd, 13, 0, 0, 0, 0, 0,
+                ])
+        ).build();
+        let mut command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        command_config.trailing_fields_to_drop = 1;
+        command_config.capture_extra = Some("extra".to_string());
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        expected_result.insert("extra".to_string(), SensorValue::String(String::new()));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_status_bits() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'1', b'0', b'1', 0x63, 0xff, 13, 0])
+        ).build();
+        let mut sensor = sensor_config("device_status", ValueType::String);
+        sensor.bits = vec!(
+            Some(serde_yaml::from_str("{name: load_on}").unwrap()),
+            None,
+            Some(serde_yaml::from_str("{name: scc_charging}").unwrap()),
+            Some(serde_yaml::from_str("{name: ac_charging}").unwrap()),
+        );
+        let mut expected_result = HashMap::new();
+        expected_result.insert("device_status".to_string(), SensorValue::String("101".to_string()));
+        expected_result.insert("load_on".to_string(), SensorValue::Boolean(true));
+        expected_result.insert("scc_charging".to_string(), SensorValue::Boolean(true));
+        assert_eq!(
+            inverter.execute_command(&command_config("QPIGS", vec!(Some(sensor)))).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_large_packet() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .packet_size(64)
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7',
+                    0x09, 0xc7, 13, 0, 0, 0, 0, 0,
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            None,
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_multiple_packets() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .packet_size(16)
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'2', b'3', b'0', b'.', b'0', b' ', b'5',
+                    b'0', b'.', b'0', b' ', b'2', b'6', b'.', b'5',
+                    b' ', b'1', 0xa8, 0x04, 13, 0, 0, 0,
+                    0, 0, 0, 0, 0, 0, 0, 0,
+                ])
+                // Reading past the end marker would overflow the response
+                .respond_endless(b'x')
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("grid_voltage", ValueType::Float)),
+            None,
+            Some(sensor_config("battery_voltage", ValueType::Float)),
+            Some(sensor_config("charging", ValueType::Integer)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("grid_voltage".to_string(), SensorValue::Float(230.0));
+        expected_result.insert("battery_voltage".to_string(), SensorValue::Float(26.5));
+        expected_result.insert("charging".to_string(), SensorValue::Integer(1));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_with_address() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[
+                    b'0', b'1', b'Q', b'P', b'I', b'G', b'S', 0x84,
+                    0x08, 13, 0, 0, 0, 0, 0, 0,
+                ])
+                .respond(&[
+                    b'(', b'0', b'1', b' ', b'2', b'3', b'3', b'.',
+                    b'7', 0x1c, 0xcb, 13, 0, 0, 0, 0,
+                ])
+        )
+            .address(Some("01".to_string()))
+            .build();
+        let command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_query_unit() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[
+                    b'0', b'1', b'Q', b'P', b'I', b'G', b'S', 0x84,
+                    0x08, 13, 0, 0, 0, 0, 0, 0,
+                ])
+                .respond(&[
+                    b'(', b'0', b'1', b' ', b'2', b'3', b'3', b'.',
+                    b'7', 0x1c, 0xcb, 13, 0, 0, 0, 0,
+                ])
+        )
+            .address(Some("01".to_string()))
+            .build();
+        assert_eq!(inverter.query_unit("QPIGS").unwrap(), " 233.7");
+    }
+
+    #[test]
+    fn test_inverter_execute_command_address_mismatch() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[
+                    b'(', b'0', b'2', b' ', b'2', b'3', b'3', b'.',
+                    b'7', 0xc4, 0x49, 13, 0, 0, 0, 0,
+                ])
+        ).build();
+        let mut command_config = command_config("QPIGS", vec!(None));
+        command_config.address = Some("01".to_string());
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::AddressMismatch {
+                address: "01".to_string(),
+                data: "02 233.7".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_header() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[b"(grid_voltage battery_voltage".as_slice(), &[0x61, 0xcb, 13]].concat())
+                .respond(&[
+                    b'(', b'2', b'3', b'3', b'.', b'7', b' ', b'2',
+                    b'6', b'.', b'5', 0x6a, 0x96, 13, 0, 0,
+                ])
+        ).build();
+        // Sensors are listed in another order than the fields
+        let mut command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("battery_voltage", ValueType::Float)),
+            Some(sensor_config("pv_voltage", ValueType::Float)),
+            Some(sensor_config("grid_voltage", ValueType::Float)),
+        ));
+        command_config.parser = ParserConfig::Header;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("grid_voltage".to_string(), SensorValue::Float(233.7));
+        expected_result.insert("battery_voltage".to_string(), SensorValue::Float(26.5));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_binary_bcd() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', 0x12, 0x34, 0x3c, 0xa1, 13, 0, 0,
+                ])
+        ).build();
+        let mut sensor = sensor_config("counter", ValueType::Integer);
+        sensor.binary_field = Some(BinaryField {
+            offset: 0,
+            width: 2,
+            decoder: FieldDecoder::Bcd,
+            endian: Endian::Big,
+            signed: false,
+        });
+        let mut command_config = command_config("QPIGS", vec!(Some(sensor)));
+        command_config.parser = ParserConfig::Binary;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("counter".to_string(), SensorValue::Integer(1234));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_text_and_binary() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[
+                    b'(', b'2', b'3', b'0', b'.', b'0', b' ', b'1', 0xc1, 0x7b, 13, 0, 0, 0, 0, 0,
+                ])
+                .respond(&[b'(', 0xff, 0xfe, 0x22, 0x49, 13, 0, 0])
+        ).build();
+        let text_cmd = command_config("QPIGS", vec!(
+            Some(sensor_config("grid_voltage", ValueType::Float)),
+            Some(sensor_config("mode", ValueType::String)),
+        ));
+        let mut sensor = sensor_config("battery_current", ValueType::Integer);
+        sensor.binary_field = Some(BinaryField {
+            offset: 0,
+            width: 2,
+            decoder: FieldDecoder::Integer,
+            endian: Endian::Big,
+            signed: true,
+        });
+        let mut binary_cmd = command_config("QDUMP", vec!(Some(sensor)));
+        binary_cmd.parser = ParserConfig::Binary;
+        binary_cmd.encoding = ResponseEncoding::Binary;
+
+        let mut expected_result = HashMap::new();
+        expected_result.insert("grid_voltage".to_string(), SensorValue::Float(230.0));
+        expected_result.insert("mode".to_string(), SensorValue::String("1".to_string()));
+        assert_eq!(inverter.execute_command(&text_cmd).unwrap(), expected_result);
+        let mut expected_result = HashMap::new();
+        expected_result.insert("battery_current".to_string(), SensorValue::Integer(-2));
+        assert_eq!(inverter.execute_command(&binary_cmd).unwrap(), expected_result);
+    }
+
+    #[test]
+    fn test_inverter_execute_command_latin1() {
+        let response = [b'(', b'2', b'5', 0xb0, b'C', 0x83, 0xe7, 13];
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&response)
+                .respond(&response)
+        ).build();
+        let mut cmd = command_config("QTEMP", vec!(
+            Some(sensor_config("temperature", ValueType::String)),
+        ));
+        cmd.encoding = ResponseEncoding::Latin1;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("temperature".to_string(), SensorValue::String("25°C".to_string()));
+        assert_eq!(inverter.execute_command(&cmd).unwrap(), expected_result);
+        cmd.encoding = ResponseEncoding::Utf8;
+        assert!(matches!(
+            inverter.execute_command(&cmd).unwrap_err(),
+            InverterError::ExpectedUtf8 { .. }
+        ));
+    }
+
+    #[test]
+    fn test_inverter_execute_command_binary_big_endian_unsigned() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[
+                    b'(', 0x01, 0x2c, 0xf9, 0xb8, 13, 0, 0,
+                ])
+        ).build();
+        let mut sensor = sensor_config("voltage", ValueType::Float);
+        sensor.binary_field = Some(BinaryField {
+            offset: 0,
+            width: 2,
+            decoder: FieldDecoder::Integer,
+            endian: Endian::Big,
+            signed: false,
+        });
+        let mut command_config = command_config("QPIGS", vec!(Some(sensor)));
+        command_config.parser = ParserConfig::Binary;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("voltage".to_string(), SensorValue::Float(300.0));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_binary_little_endian_signed() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[
+                    b'(', 0x9c, 0xff, 0xff, 0xff, 0x51, 0x58, 13,
+                ])
+        ).build();
+        let mut sensor = sensor_config("battery_current", ValueType::Integer);
+        sensor.binary_field = Some(BinaryField {
+            offset: 0,
+            width: 4,
+            decoder: FieldDecoder::Integer,
+            endian: Endian::Little,
+            signed: true,
+        });
+        let mut command_config = command_config("QPIGS", vec!(Some(sensor)));
+        command_config.parser = ParserConfig::Binary;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("battery_current".to_string(), SensorValue::Integer(-100));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_binary_out_of_range() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[
+                    b'(', 0x12, 0x34, 0x3c, 0xa1, 13, 0, 0,
+                ])
+        ).build();
+        let mut sensor = sensor_config("counter", ValueType::Integer);
+        sensor.binary_field = Some(BinaryField {
+            offset: 1,
+            width: 2,
+            decoder: FieldDecoder::Bcd,
+            endian: Endian::Big,
+            signed: false,
+        });
+        let mut command_config = command_config("QPIGS", vec!(Some(sensor)));
+        command_config.parser = ParserConfig::Binary;
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap_err(),
+            InverterError::ParseResponse {
+                source: ParseResponseError::FieldOutOfRange {
+                    sensor: "counter".to_string(),
+                    offset: 1,
+                    end: 3,
+                    len: 2,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_custom_crc() {
+        // CRC-16/X-25 has reflected input and output and a final xor
+        let crc_config = CrcConfig {
+            poly: 0x1021,
+            init: 0xffff,
+            refin: true,
+            refout: true,
+            xorout: 0xffff,
+        };
+        let crc = custom_crc(&crc_config);
+        assert_eq!(calc_crc(crc, b"123456789"), 0x906e);
+        // Built once per algorithm
+        assert!(std::ptr::eq(crc, custom_crc(&crc_config.clone())));
+
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[b'Q', b'I', b'D', 0x8a, 0xad, 13, 0, 0])
+                .respond(&[b'(', b'0', 0xd3, 0x37, 13, 0, 0, 0])
+        )
+            .crc(crc)
+            .build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_catalog_crc() {
+        assert_eq!(calc_crc(catalog_crc(CrcAlgorithm::Xmodem), b"123456789"), 0x31c3);
+        assert_eq!(calc_crc(catalog_crc(CrcAlgorithm::CcittFalse), b"123456789"), 0x29b1);
+        assert_eq!(calc_crc(catalog_crc(CrcAlgorithm::Modbus), b"123456789"), 0x4b37);
+
+        // Same command is encoded with a different crc
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[b'Q', b'I', b'D', 0xd6, 0xea, 13, 0, 0])
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0])
+        )
+            .crc(catalog_crc(CrcAlgorithm::Xmodem))
+            .build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(&[b'Q', b'I', b'D', 0xb2, 0x17, 13, 0, 0])
+                .respond(&[b'(', b'0', 0xa4, 0x1f, 13, 0, 0, 0])
+        )
+            .crc(catalog_crc(CrcAlgorithm::Modbus))
+            .build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_check_frame() {
+        assert_eq!(
+            check_frame(
+                &DEFAULT_CRC, &[b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7', 0x09, 0xc7]
+            ).unwrap(),
+            b"0 233.7"
+        );
+        assert_eq!(
+            check_frame(&DEFAULT_CRC, &[b'(', b'0', 0x00, 0x00]).unwrap_err(),
+            InverterError::InvalidCrc {
+                expected: "0xb91c".to_string(),
+                actual: "0x0000".to_string(),
+                data: "(0".to_string(),
+            }
+        );
+        assert_eq!(
+            check_frame(&DEFAULT_CRC, &[b'(', 0x00]).unwrap_err(),
+            InverterError::ResponseTooShort { len: 2 }
+        );
+        assert_eq!(
+            check_frame(&DEFAULT_CRC, b"0 233.7").unwrap_err(),
+            InverterError::MissingResponseMarker
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_end_marker_in_crc() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'0', b'0', b'0', b'4', b'7', 0x52, 13,
+                    13, 0, 0, 0, 0, 0, 0, 0,
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            Some(sensor_config("sensor1", ValueType::Integer)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Integer(47));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_partial_reads() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .read_limit(3)
+                .expect_request(ENCODED_STATUS_CMD)
+                .respond(&[
+                    b'(', b'0', b' ', b'2', b'3', b'3', b'.', b'7',
+                    0x09, 0xc7, 13,
+                ])
+        ).build();
+        let command_config = command_config("QPIGS", vec!(
+            None,
+            Some(sensor_config("sensor1", ValueType::Float)),
+        ));
+        let mut expected_result = HashMap::new();
+        expected_result.insert("sensor1".to_string(), SensorValue::Float(233.7));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_zero_byte_at_read_boundary() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .read_limit(3)
+                .respond(&[b'(', 0x12, 0x00, 0x4a, 0x76, 13, 0, 0])
+        ).build();
+        let mut sensor = sensor_config("counter", ValueType::Integer);
+        sensor.binary_field = Some(BinaryField {
+            offset: 0,
+            width: 2,
+            decoder: FieldDecoder::Bcd,
+            endian: Endian::Big,
+            signed: false,
+        });
+        let mut command_config = command_config("QPIGS", vec!(Some(sensor)));
+        command_config.parser = ParserConfig::Binary;
+        let mut expected_result = HashMap::new();
+        expected_result.insert("counter".to_string(), SensorValue::Integer(1200));
+        assert_eq!(
+            inverter.execute_command(&command_config).unwrap(),
+            expected_result
+        );
+    }
+
+    #[test]
+    fn test_inverter_execute_command_padding_after_end_marker() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[b'(', b'0', 0xb9, 0x1c, 13, b'x', b'x', b'x'])
+        ).build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_inverter_query_length_prefixed() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .read_limit(3)
+                .respond(&[0x00, 0x05, b'(', b'0', 0xb9, 0x1c, 13, 0])
+        )
+            .framing(FramingConfig::LengthPrefixed(LengthPrefixConfig {
+                prefix_size: 2,
+                endian: Endian::Big,
+            }))
+            .build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_inverter_query_length_prefixed_little_endian() {
+        let mut inverter = Inverter::builder(
+            MockDevice::new()
+                .respond(&[0x04, 0x00, b'(', b'0', 0xb9, 0x1c, 0, 0])
+        )
+            .framing(FramingConfig::LengthPrefixed(LengthPrefixConfig {
+                prefix_size: 2,
+                endian: Endian::Little,
+            }))
+            .build();
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_inverter_query_length_prefixed_too_long() {
+        let mut inverter = Inverter::builder(MockDevice::new().respond(&[0x01, 0x00, b'(']))
+            .framing(FramingConfig::LengthPrefixed(LengthPrefixConfig {
+                prefix_size: 2,
+                endian: Endian::Big,
+            }))
+            .max_response_bytes(16)
+            .build();
+        assert_eq!(
+            inverter.query("QID").unwrap_err(),
+            InverterError::ResponseTooLong { max: 16 }
+        );
+    }
 }
@@ -1,35 +1,141 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, LazyLock};
+use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 
-use rusb::{devices, supports_detach_kernel_driver, UsbContext, DeviceHandle};
+use crc::Crc;
+
+use rusb::{devices, supports_detach_kernel_driver, Device, DeviceHandle, GlobalContext, UsbContext};
 
 use paho_mqtt as mqtt;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use snafu::{Whatever, whatever, ResultExt};
 
+mod aggregate;
 mod config;
-use crate::config::{Config, InverterConfig, MqttConfig, RequestParams, ResponseParams};
+mod daemon;
+use crate::config::{
+    CommandConfig,
+    Component,
+    Config,
+    DeviceConfig,
+    FieldDecoder,
+    FramingConfig,
+    InfluxDbConfig,
+    InverterConfig,
+    MqttConfig,
+    MqttTls,
+    ParserConfig,
+    RequestParams,
+    ResponseEncoding,
+    ResponseParams,
+    SensorConfig,
+    SensorGroupConfig,
+    SettingConfig,
+    ShortResponsePolicy,
+    StateClass,
+    UsbConfig,
+    ValueType,
+};
+mod derived;
+mod diagnostics;
+use diagnostics::Diagnostics;
+mod format;
+mod generate;
 mod hass;
+#[cfg(feature = "influxdb")]
+mod influxdb;
 mod inverter;
-use inverter::{DeviceError, Inverter, InverterDevice, MAX_COMMAND_LENGTH, SensorValue};
+mod parse;
+mod postprocess;
+mod raw;
+use raw::RawInverterDevice;
+mod reopen;
+use reopen::ReopeningUsbDevice;
+mod retry;
+use retry::RetryLimit;
+mod serial;
+use serial::SerialInverterDevice;
+mod snapshot;
+mod tcp;
+use tcp::TcpInverterDevice;
+#[cfg(test)]
+mod testutil;
+mod throttle;
+use throttle::LogThrottle;
+use inverter::{
+    catalog_crc,
+    check_frame,
+    custom_crc,
+    DeviceError,
+    Inverter,
+    InverterDevice,
+    DEFAULT_CRC,
+    MAX_COMMAND_LENGTH,
+    REPORT_SIZE,
+    SensorValue,
+};
 
-const INVERTER_QUERY_INTERVAL_SECS: u64 = 30;
 const INVERTER_RETRY_DELAY_SECS: u64 = 10;
+const PROBE_MAX_DELAY_SECS: u64 = 300;
 const MQTT_RETRY_DELAY_SECS: u64 = 10;
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+// Home Assistant shows the entity state as unknown for this payload
+const UNAVAILABLE_STATE: &str = "None";
+const DIAGNOSTICS_ICON: &str = "mdi:counter";
+const CONNECTED_SENSOR: &str = "connected";
+const BAUD_RATE_SENSOR: &str = "baud_rate";
+const BINARY_SENSOR_ON: &str = "ON";
+const BINARY_SENSOR_OFF: &str = "OFF";
 const MQTT_MIN_RETRY_INTERVAL_SECS: u64 = 1;
 const MQTT_MAX_RETRY_INTERVAL_SECS: u64 = 60;
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Set by SIGINT and SIGTERM handlers to stop polling
+static SHUTDOWN_REQUESTED: LazyLock<Arc<AtomicBool>> = LazyLock::new(Arc::default);
+// Incremented by SIGHUP handler, every polling thread reloads its config once per change
+static RELOAD_REQUESTS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Parser, Debug)]
 struct Args {
-    config: PathBuf,
+    #[arg(required_unless_present_any = ["generate_config", "verify_frame", "once"])]
+    config: Option<PathBuf>,
+    /// Send a query to the inverter, check the response and exit
+    #[arg(long, value_name = "COMMAND", num_args = 0..=1, default_missing_value = "QID")]
+    self_test: Option<String>,
+    /// Probe the USB device with well-known queries and print a config skeleton
+    #[arg(long, value_name = "VID:PID", value_parser = generate::parse_usb_id)]
+    generate_config: Option<(u16, u16)>,
+    /// Remove entities of the configured inverter from Home Assistant and exit
+    #[arg(long)]
+    clear_discovery: bool,
+    /// Check crc of a response frame given as hex bytes and print its data,
+    /// with the crc of the inverter when a config is given
+    // Full path stops clap from treating the bytes as multiple arguments
+    #[arg(long, value_name = "HEX", value_parser = parse_hex)]
+    verify_frame: Option<std::vec::Vec<u8>>,
+    /// Skip invalid commands and sensors of the config with a warning instead of exiting
+    #[arg(long)]
+    lenient: bool,
+    /// Serve queries over a Unix socket instead of publishing to MQTT
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// Send a query to the daemon listening on --socket, print the response and exit
+    #[arg(long, value_name = "COMMAND", requires = "socket")]
+    once: Option<String>,
 }
 
 struct InverterUSBDevice<T: UsbContext> {
     usb_dev: DeviceHandle<T>,
+    max_packet_size: u8,
     request_params: RequestParams,
     response_params: ResponseParams,
 }
@@ -37,11 +143,13 @@ struct InverterUSBDevice<T: UsbContext> {
 impl<T: UsbContext> InverterUSBDevice<T> {
     fn new(
         usb_dev: DeviceHandle<T>,
+        max_packet_size: u8,
         request_params: RequestParams,
         response_params: ResponseParams,
     ) -> Self {
         Self {
             usb_dev,
+            max_packet_size,
             request_params,
             response_params,
         }
@@ -50,14 +158,18 @@ impl<T: UsbContext> InverterUSBDevice<T> {
 
 impl<T: UsbContext> InverterDevice for InverterUSBDevice<T> {
     fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
-        self.usb_dev.write_control(
-            self.request_params.request_type,
-            self.request_params.request,
-            self.request_params.value,
-            self.request_params.index,
-            buf,
-            Duration::from_millis(self.request_params.timeout_ms.into())
-        ).map_err(|e| DeviceError::Usb { source: e })
+        let mut written = 0;
+        for report in buf.chunks(REPORT_SIZE) {
+            written += self.usb_dev.write_control(
+                self.request_params.request_type,
+                self.request_params.request,
+                self.request_params.value,
+                self.request_params.index,
+                report,
+                Duration::from_millis(self.request_params.timeout_ms.into())
+            ).map_err(|e| DeviceError::Usb { source: e })?;
+        }
+        Ok(written)
     }
 
     fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
@@ -67,6 +179,10 @@ impl<T: UsbContext> InverterDevice for InverterUSBDevice<T> {
             Duration::from_millis(self.response_params.timeout_ms.into())
         ).map_err(|e| DeviceError::Usb { source: e })
     }
+
+    fn packet_size(&self) -> usize {
+        self.max_packet_size.into()
+    }
 }
 
 fn main() -> Result<(), Whatever> {
@@ -74,95 +190,861 @@ fn main() -> Result<(), Whatever> {
 
     let args = Args::parse();
 
-    let config_file = File::open(args.config)
-        .with_whatever_context(|e| format!("Cannot open config file: {e}"))?;
-    let config_reader = BufReader::new(config_file);
-    let config: Config = serde_yaml::from_reader(config_reader)
-        .with_whatever_context(|e| format!("Error when parsing config file: {e}"))?;
+    if let Some(frame) = &args.verify_frame {
+        let Some(config_path) = &args.config else {
+            return verify_frame(&DEFAULT_CRC, frame);
+        };
+        let config = load_config(config_path, args.lenient)?;
+        let [inverter_cfg] = config.inverters.as_slice() else {
+            whatever!("Verifying a frame is only supported with a single inverter");
+        };
+        return verify_frame(inverter_crc(inverter_cfg), frame);
+    }
+    if let Some((vendor_id, product_id)) = args.generate_config {
+        return generate_config(vendor_id, product_id);
+    }
+    if let (Some(cmd), Some(socket)) = (&args.once, &args.socket) {
+        println!("{}", daemon::query(socket, cmd)?);
+        return Ok(());
+    }
+    let Some(config_path) = &args.config else {
+        whatever!("Config file is required");
+    };
+    let config = load_config(config_path, args.lenient)?;
+    for (i, inverter_cfg) in config.inverters.iter().enumerate() {
+        if config.inverters[..i].iter().any(|other| other.id == inverter_cfg.id) {
+            whatever!("'{}' inverter is defined more than once", inverter_cfg.id);
+        }
+        if let Some(other) = config.inverters[..i].iter().find(|other| {
+            shares_usb_device(&inverter_cfg.device, &other.device)
+        }) {
+            whatever!(
+                "'{}' and '{}' inverters have the same USB ids, set device_path for both of them",
+                other.id, inverter_cfg.id
+            );
+        }
+        if let Err(e) = validate_inverter(inverter_cfg) {
+            whatever!("Invalid '{}' inverter: {e}", inverter_cfg.id);
+        }
+    }
+    for (component, opts) in config.mqtt.discovery_publish.iter() {
+        if !(0..=2).contains(&opts.qos) {
+            whatever!("Invalid QoS {} for {} discovery", opts.qos, component.as_str());
+        }
+    }
+    if !(0..=2).contains(&config.mqtt.discovery_qos) {
+        whatever!("Invalid discovery QoS {}", config.mqtt.discovery_qos);
+    }
+    if !(0..=2).contains(&config.mqtt.state_qos) {
+        whatever!("Invalid state QoS {}", config.mqtt.state_qos);
+    }
+    if config.influxdb.is_some() && !cfg!(feature = "influxdb") {
+        whatever!("InfluxDB output requires building with the 'influxdb' feature");
+    }
+    if config.mqtt.topic_prefix.contains(['+', '#']) {
+        whatever!("Invalid topic prefix: '{}'", config.mqtt.topic_prefix);
+    }
+
+    if args.clear_discovery {
+        let mqtt_client = establish_mqtt_conn(&config.mqtt, &config.inverters)?;
+        for inverter_cfg in config.inverters.iter() {
+            clear_discovery(inverter_cfg, &config.mqtt, &mqtt_client)?;
+        }
+        if let Some(topic) = shared_availability_topic(&config.mqtt, &config.inverters) {
+            clear_retained(&mqtt_client, &topic)?;
+        }
+        // Otherwise the will message would bring back the cleared availability
+        return mqtt_client.disconnect(None)
+            .with_whatever_context(|e| format!("Cannot disconnect from mqtt server: {e}"));
+    }
+
+    if let Some(cmd) = &args.self_test {
+        let [inverter_cfg] = config.inverters.as_slice() else {
+            whatever!("Self-test is only supported with a single inverter");
+        };
+        return open_and_serve(inverter_cfg, Target::SelfTest(cmd)).map(|_| ());
+    }
+    if let Some(socket) = &args.socket {
+        let [inverter_cfg] = config.inverters.as_slice() else {
+            whatever!("Serving over a socket is only supported with a single inverter");
+        };
+        return open_and_serve(inverter_cfg, Target::Socket(socket)).map(|_| ());
+    }
+
+    let mqtt_client = establish_mqtt_conn(&config.mqtt, &config.inverters)?;
+    handle_signals()?;
+    let bridge = Bridge {
+        config_path,
+        lenient: args.lenient,
+        mqtt_cfg: &config.mqtt,
+        mqtt_client: &mqtt_client,
+        availability_topic: shared_availability_topic(&config.mqtt, &config.inverters),
+        influxdb_cfg: config.influxdb.as_ref(),
+    };
+    poll_inverters(&config.inverters, &bridge)
+}
+
+// Without a device path both inverters would open the first device with matching ids
+fn shares_usb_device(device: &DeviceConfig, other: &DeviceConfig) -> bool {
+    let (DeviceConfig::Usb(usb_cfg), DeviceConfig::Usb(other_cfg)) = (device, other) else {
+        return false;
+    };
+    usb_cfg.vendor_id == other_cfg.vendor_id
+        && usb_cfg.product_id == other_cfg.product_id
+        && (usb_cfg.device_path.is_none() || other_cfg.device_path.is_none())
+}
+
+fn validate_inverter(inverter_cfg: &InverterConfig) -> Result<(), Whatever> {
+    if let FramingConfig::LengthPrefixed(prefix) = &inverter_cfg.framing {
+        if !(1..=4).contains(&prefix.prefix_size) {
+            whatever!("Length prefix size must be from 1 to 4 bytes");
+        }
+    }
+    if inverter_cfg.crc.is_some() && inverter_cfg.crc_algorithm.is_some() {
+        whatever!("Only one of crc and crc_algorithm can be set");
+    }
+    if let Some(crc) = &inverter_cfg.crc {
+        // Polynomial without the lowest bit does not detect single bit errors
+        if crc.poly & 1 == 0 {
+            whatever!("CRC polynomial {:#06x} must be odd", crc.poly);
+        }
+    }
+    if inverter_cfg.query_interval_secs == 0 {
+        whatever!("Query interval must be positive");
+    }
+    if inverter_cfg.rediscovery_interval_secs == Some(0) {
+        whatever!("Rediscovery interval must be positive");
+    }
+    if inverter_cfg.post_process_timeout_ms == 0 {
+        whatever!("Post-process timeout must be positive");
+    }
+    inverter_cfg.validate_pad_length()?;
+    if let Some(probe_cmd) = &inverter_cfg.probe_command {
+        let address = inverter_cfg.address.as_deref().unwrap_or("");
+        if probe_cmd.is_empty() || address.len() + probe_cmd.len() > MAX_COMMAND_LENGTH {
+            whatever!("Invalid probe command: '{probe_cmd}'");
+        }
+    }
     // Check commands length
-    for command in config.inverter.commands.iter() {
-        let cmd = &command.command;
+    for command in inverter_cfg.commands.iter() {
+        let address = command.address.as_ref().or(inverter_cfg.address.as_ref());
+        let cmd = format!("{}{}", address.map_or("", |a| a.as_str()), command.full_command());
         if cmd.len() > MAX_COMMAND_LENGTH {
             whatever!("'{cmd}' command is too long, maximum {MAX_COMMAND_LENGTH} chars");
         }
+        if command.interval_secs == Some(0) {
+            whatever!("'{cmd}' command interval must be positive");
+        }
+        if let Some(separator) = &command.section_separator {
+            if separator.is_empty() || command.parser != ParserConfig::Whitespace {
+                whatever!("'{cmd}' command must have a non-empty separator and whitespace parser");
+            }
+            if command.section_sizes.iter().sum::<usize>() != command.sensors.len() {
+                whatever!("'{cmd}' command section sizes must add up to the number of sensors");
+            }
+        }
+        if let Some(capture_extra) = &command.capture_extra {
+            if command.parser != ParserConfig::Whitespace {
+                whatever!("'{cmd}' command can capture extra fields only with whitespace parser");
+            }
+            if command.sensors.iter().flatten().any(|s| &s.name == capture_extra) {
+                whatever!("'{cmd}' command captures extra fields into its own sensor");
+            }
+        }
+        // Binary parser reads raw bytes regardless of the encoding
+        if command.encoding == ResponseEncoding::Binary && command.parser != ParserConfig::Binary {
+            whatever!("'{cmd}' command with binary encoding must use binary parser");
+        }
+        match &command.parser {
+            ParserConfig::FixedWidth(fixed_width)
+                if fixed_width.widths.len() != command.sensors.len() =>
+            {
+                whatever!("'{cmd}' command must have a field width for every sensor");
+            }
+            ParserConfig::Binary => {
+                for sensor in command.sensors.iter().filter_map(|s| s.as_ref()) {
+                    match &sensor.binary_field {
+                        None => whatever!(
+                            "'{}' sensor of a binary command has no binary_field", sensor.name
+                        ),
+                        Some(field) if field.decoder == FieldDecoder::Integer
+                            && ![1, 2, 4].contains(&field.width) =>
+                        {
+                            whatever!("'{}' sensor must be 1, 2 or 4 bytes wide", sensor.name);
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for sensor in inverter_cfg.commands.iter().flat_map(|cmd| cmd.sensors.iter().flatten()) {
+        let Some(fallback) = &sensor.fallback else {
+            continue;
+        };
+        let fallback_cmd = inverter_cfg.commands.iter()
+            .find(|cmd| cmd.sensors.iter().flatten().any(|s| &s.name == fallback));
+        match fallback_cmd {
+            Some(cmd) if !cmd.sensors.iter().flatten().any(|s| s.name == sensor.name) => {}
+            _ => whatever!(
+                "'{}' sensor must fall back to a sensor of another command, got '{fallback}'",
+                sensor.name
+            ),
+        }
+    }
+    let invalid_sensors = inverter_cfg.sensors()
+        .filter_map(|sensor| sensor.validate().err())
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    if !invalid_sensors.is_empty() {
+        whatever!("Invalid sensors:\n{}", invalid_sensors.join("\n"));
+    }
+    for sensor in inverter_cfg.sensors() {
+        match sensor.radix {
+            Some(8 | 10 | 16) if matches!(sensor.value_type, ValueType::Integer) => {}
+            Some(radix) => whatever!("'{}' sensor cannot have radix {radix}", sensor.name),
+            None => {}
+        }
+        let is_numeric = matches!(sensor.value_type, ValueType::Integer | ValueType::Float);
+        if !is_numeric && (sensor.scale.is_some() || sensor.offset.is_some())
+        {
+            whatever!("'{}' sensor is not numeric and cannot be scaled", sensor.name);
+        }
+        if matches!(sensor.value_type, ValueType::DateTime) && sensor.datetime_format.is_none() {
+            whatever!("'{}' datetime sensor has no datetime_format", sensor.name);
+        }
+        if let Some(offset) = &sensor.utc_offset {
+            if offset.parse::<chrono::FixedOffset>().is_err() {
+                whatever!("'{}' sensor has invalid UTC offset '{offset}'", sensor.name);
+            }
+        }
+        if let Some(aggregate) = &sensor.aggregate {
+            if !is_numeric {
+                whatever!("'{}' sensor is not numeric and cannot be aggregated", sensor.name);
+            }
+            if aggregate.window == 0 {
+                whatever!("'{}' sensor must aggregate at least 1 sample", sensor.name);
+            }
+        }
+        match sensor.value_type {
+            ValueType::Enum if sensor.map.is_empty() => {
+                whatever!("'{}' enum sensor has no map", sensor.name);
+            }
+            ValueType::Enum => {}
+            _ if !sensor.map.is_empty() => {
+                whatever!("'{}' sensor must be an enum to have a map", sensor.name);
+            }
+            _ => {}
+        }
+        if !sensor.bits.is_empty() && !matches!(sensor.value_type, ValueType::String) {
+            whatever!("'{}' sensor must be a string to expand into bits", sensor.name);
+        }
+        if let Some(device) = &sensor.device {
+            if inverter_cfg.sub_device(device).is_none() {
+                whatever!("'{}' sensor refers to unknown '{device}' sub device", sensor.name);
+            }
+        }
+    }
+    for (_, bit) in inverter_cfg.status_bits() {
+        if inverter_cfg.sensor(&bit.name).is_some() {
+            whatever!("'{}' status bit clashes with a sensor of the same name", bit.name);
+        }
+    }
+    for setting in inverter_cfg.settings.iter() {
+        if setting.options.is_empty() {
+            whatever!("'{}' setting has no options", setting.name);
+        }
+        if inverter_cfg.sensor(&setting.name).is_some() {
+            whatever!("'{}' setting clashes with a sensor of the same name", setting.name);
+        }
+        if let Some(device) = &setting.device {
+            if inverter_cfg.sub_device(device).is_none() {
+                whatever!("'{}' setting refers to unknown '{device}' sub device", setting.name);
+            }
+        }
+        if setting.verify_after_write {
+            let read_back_cmd = setting.read_back.as_ref()
+                .and_then(|sensor| read_back_command(inverter_cfg, sensor));
+            if read_back_cmd.is_none() {
+                whatever!("'{}' setting must be read back from a command sensor", setting.name);
+            }
+        }
+    }
+    for (i, sub_device) in inverter_cfg.sub_devices.iter().enumerate() {
+        if inverter_cfg.sub_devices[..i].iter().any(|d| d.id == sub_device.id) {
+            whatever!("'{}' sub device is defined more than once", sub_device.id);
+        }
+    }
+    for group in inverter_cfg.sensor_groups.iter() {
+        if group.sensors.is_empty() {
+            whatever!("'{}' sensor group has no sensors", group.name);
+        }
+        if inverter_cfg.sensor(&group.name).is_some() {
+            whatever!("'{}' sensor group clashes with a sensor of the same name", group.name);
+        }
+        if let Some(name) = group.sensors.iter().find(|&n| inverter_cfg.sensor(n).is_none()) {
+            whatever!("'{}' sensor group refers to unknown '{name}' sensor", group.name);
+        }
+    }
+    inverter_cfg.validate_availability()?;
+    for sensor in inverter_cfg.sensors() {
+        for topic in sensor.extra_state_topics.iter() {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                whatever!("'{}' sensor has invalid extra state topic: '{topic}'", sensor.name);
+            }
+        }
+        // Last reset is only meaningful for the total state class
+        if sensor.last_reset_value_template.is_some()
+            && sensor.state_class != Some(StateClass::Total)
+        {
+            whatever!(
+                "'{}' sensor has last_reset_value_template but its state_class is not total",
+                sensor.name
+            );
+        }
+    }
+
+    if inverter_cfg.reopen_each_cycle
+        && !matches!(inverter_cfg.device, DeviceConfig::Usb(_) | DeviceConfig::Tcp(_))
+    {
+        whatever!("Only usb and tcp devices can be reopened every cycle");
+    }
+    Ok(())
+}
+
+// Accepts hex bytes with optional whitespace, e.g. "28 4e 41 4b 73 73 0d"
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let byte = pair.iter().collect::<String>();
+            u8::from_str_radix(&byte, 16).map_err(|e| format!("invalid hex byte '{byte}': {e}"))
+        })
+        .collect()
+}
+
+// Output is accepted by parse_hex
+fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(" ")
+}
+
+fn verify_frame(crc: &Crc<u16>, frame: &[u8]) -> Result<(), Whatever> {
+    let frame = frame.strip_suffix(b"\r").unwrap_or(frame);
+    match check_frame(crc, frame) {
+        Ok(data) => {
+            println!("Valid frame: '{}'", String::from_utf8_lossy(data));
+            Ok(())
+        }
+        Err(e) => whatever!("Invalid frame: {e}"),
+    }
+}
+
+fn load_config(path: &Path, lenient: bool) -> Result<Config, Whatever> {
+    let config_file = File::open(path)
+        .with_whatever_context(|e| format!("Cannot open config file: {e}"))?;
+    let config_reader = BufReader::new(config_file);
+    // Strict parsing of the top level sections stays in the lenient mode
+    let config: Result<Config, _> = if lenient {
+        serde_yaml::from_reader(config_reader).and_then(|mut value: serde_yaml::Value| {
+            if let Some(inverter) = value.get_mut("inverter") {
+                log_skipped(config::drop_invalid_entries(inverter));
+            }
+            if let Some(serde_yaml::Value::Sequence(inverters)) = value.get_mut("inverters") {
+                for inverter in inverters.iter_mut() {
+                    log_skipped(config::drop_invalid_entries(inverter));
+                }
+            }
+            serde_yaml::from_value(value)
+        })
+    } else {
+        serde_yaml::from_reader(config_reader)
+    };
+    let mut config = config
+        .with_whatever_context(|e| format!("Error when parsing config file: {e}"))?;
+
+    for inverter_cfg in config.inverters.iter_mut() {
+        let Some(commands_file) = &inverter_cfg.commands_file else {
+            continue;
+        };
+        // Relative paths are resolved against the config file directory
+        let commands_path = path.parent().unwrap_or(Path::new(".")).join(commands_file);
+        let commands_file = File::open(&commands_path)
+            .with_whatever_context(|e| {
+                format!("Cannot open commands file {}: {e}", commands_path.display())
+            })?;
+        let commands_reader = BufReader::new(commands_file);
+        let commands: Result<Vec<CommandConfig>, _> = if lenient {
+            serde_yaml::from_reader(commands_reader).and_then(|mut value| {
+                log_skipped(config::drop_invalid_commands(&mut value));
+                serde_yaml::from_value(value)
+            })
+        } else {
+            serde_yaml::from_reader(commands_reader)
+        };
+        let mut commands = commands
+            .with_whatever_context(|e| {
+                format!("Error when parsing commands file {}: {e}", commands_path.display())
+            })?;
+        commands.append(&mut inverter_cfg.commands);
+        inverter_cfg.commands = commands;
     }
 
+    Ok(config)
+}
+
+fn log_skipped(skipped: Vec<String>) {
+    for msg in skipped {
+        log::warn!("{msg}");
+    }
+}
+
+fn open_usb_device(
+    usb_cfg: &UsbConfig,
+    max_retries: Option<u32>,
+    search_timeout: Option<Duration>,
+) -> Result<InverterUSBDevice<GlobalContext>, Whatever> {
     if !supports_detach_kernel_driver() {
         whatever!("Detaching kernel driver from USB device is not supported");
     }
 
+    let started_at = Instant::now();
+    let mut retry_limit = RetryLimit::new(max_retries);
+    loop {
+        log::info!(
+            "Looking for USB device {:04x}:{:04x}", usb_cfg.vendor_id, usb_cfg.product_id
+        );
+        if let Some(dev) = try_open_usb_device(usb_cfg)? {
+            return Ok(dev);
+        }
+        if let Some(timeout) = search_timeout.filter(|&timeout| started_at.elapsed() >= timeout) {
+            whatever!("USB device is not found within {} seconds", timeout.as_secs());
+        }
+        retry_limit.retry("waiting for the USB device")?;
+        log::warn!("Devices are not found. Waiting");
+        sleep(Duration::from_secs(INVERTER_RETRY_DELAY_SECS));
+    }
+}
+
+// None when the device is not plugged in
+fn try_open_usb_device(
+    usb_cfg: &UsbConfig,
+) -> Result<Option<InverterUSBDevice<GlobalContext>>, Whatever> {
+    let Some((dev, max_packet_size)) = find_usb_device(usb_cfg)? else {
+        return Ok(None);
+    };
+
+    let mut dev = dev.open()
+        .with_whatever_context(|e| format!("Cannot open USB device: {e}"))?;
+    dev.set_auto_detach_kernel_driver(true)
+       .with_whatever_context(|e| format!("Cannot detach USB kernel driver: {e}"))?;
+    dev.claim_interface(usb_cfg.interface)
+       .with_whatever_context(|e| format!("Cannot claim USB interface: {e}"))?;
+
+    Ok(Some(InverterUSBDevice::new(
+        dev,
+        max_packet_size,
+        usb_cfg.request_params.clone(),
+        usb_cfg.response_params.clone()
+    )))
+}
+
+fn find_usb_device(
+    usb_cfg: &UsbConfig,
+) -> Result<Option<(Device<GlobalContext>, u8)>, Whatever> {
+    let location = match &usb_cfg.device_path {
+        Some(path) => match usb_location(path)? {
+            Some(location) => Some(location),
+            None => return Ok(None),
+        },
+        None => None,
+    };
     let dev_list = devices()
         .with_whatever_context(|e| format!("Error when fetching USB devices: {e}"))?;
-    let mut dev_iter = dev_list.iter();
-    let dev = loop {
-        if let Some(dev) = dev_iter.next() {
-            let dev_descr = dev.device_descriptor()
-                .with_whatever_context(|e| format!("Error getting USB device descriptor: {e}"))?;
-            let vendor_id = config.inverter.usb.vendor_id;
-            let product_id = config.inverter.usb.product_id;
-            if (dev_descr.vendor_id(), dev_descr.product_id()) == (vendor_id, product_id) {
-                log::info!(
-                    "Found device: {}:{}",
-                    &format!("{:#06x}", vendor_id)[2..],
-                    &format!("{:#06x}", product_id)[2..],
-                );
-                break Some((dev, dev_descr.max_packet_size()));
-            }
-        } else {
-            break None;
+    for dev in dev_list.iter() {
+        if location.is_some_and(|location| location != (dev.bus_number(), dev.address())) {
+            continue;
+        }
+        let dev_descr = dev.device_descriptor()
+            .with_whatever_context(|e| format!("Error getting USB device descriptor: {e}"))?;
+        let vendor_id = usb_cfg.vendor_id;
+        let product_id = usb_cfg.product_id;
+        let ids = (dev_descr.vendor_id(), dev_descr.product_id());
+        if ids != (vendor_id, product_id) && location.is_some() {
+            whatever!(
+                "Device path points to {:04x}:{:04x}, expected {vendor_id:04x}:{product_id:04x}",
+                ids.0, ids.1
+            );
+        }
+        if ids == (vendor_id, product_id) {
+            log::info!(
+                "Found device: {}:{}",
+                &format!("{:#06x}", vendor_id)[2..],
+                &format!("{:#06x}", product_id)[2..],
+            );
+            return Ok(Some((dev, dev_descr.max_packet_size())));
         }
+    }
+    Ok(None)
+}
+
+// Bus number and address of the device node a udev symlink points to,
+// none while the device is unplugged and the symlink is missing
+fn usb_location(path: &Path) -> Result<Option<(u8, u8)>, Whatever> {
+    let Ok(node) = std::fs::canonicalize(path) else {
+        return Ok(None);
     };
+    // Device nodes are /dev/bus/usb/<bus>/<address>
+    let mut components = node.iter().rev().map(|c| c.to_str().and_then(|c| c.parse().ok()));
+    match (components.next().flatten(), components.next().flatten()) {
+        (Some(address), Some(bus)) => Ok(Some((bus, address))),
+        _ => whatever!("{} is not a USB device node", node.display()),
+    }
+}
+
+// MQTT connection shared by the polling threads of all inverters
+struct Bridge<'a> {
+    // Inverter sections are reloaded from the config file on SIGHUP
+    config_path: &'a Path,
+    lenient: bool,
+    mqtt_cfg: &'a MqttConfig,
+    mqtt_client: &'a mqtt::Client,
+    // Will message of a connection shared by several inverters
+    availability_topic: Option<String>,
+    #[cfg_attr(not(feature = "influxdb"), allow(dead_code))]
+    influxdb_cfg: Option<&'a InfluxDbConfig>,
+}
 
+// What an opened inverter is used for
+enum Target<'a> {
+    SelfTest(&'a str),
+    Socket(&'a Path),
+    Poll(&'a Bridge<'a>, &'a ConnectionEvents),
+}
+
+// Some with the reloaded config when the device has to be reopened with it
+fn open_and_serve(
+    inverter_cfg: &InverterConfig,
+    target: Target,
+) -> Result<Option<InverterConfig>, Whatever> {
+    match &inverter_cfg.device {
+        DeviceConfig::Usb(usb_cfg) => {
+            let search_timeout = inverter_cfg.device_search_timeout_secs.map(Duration::from_secs);
+            let dev = open_usb_device(usb_cfg, inverter_cfg.max_retries, search_timeout)?;
+            let dev = ReopeningUsbDevice::new(dev, || try_open_usb_device(usb_cfg));
+            serve(dev, inverter_cfg, target)
+        }
+        DeviceConfig::Raw(raw_cfg) => {
+            let dev = RawInverterDevice::open(raw_cfg)
+                .with_whatever_context(|e| {
+                    format!("Cannot open {}: {e}", raw_cfg.path.display())
+                })?;
+            serve(dev, inverter_cfg, target)
+        }
+        DeviceConfig::Serial(serial_cfg) => {
+            let dev = SerialInverterDevice::open(serial_cfg)
+                .with_whatever_context(|e| {
+                    format!("Cannot open {}: {e}", serial_cfg.path.display())
+                })?;
+            serve(dev, inverter_cfg, target)
+        }
+        DeviceConfig::Tcp(tcp_cfg) => serve(TcpInverterDevice::new(tcp_cfg), inverter_cfg, target),
+    }
+}
 
+fn serve<T: InverterDevice>(
+    dev: T,
+    inverter_cfg: &InverterConfig,
+    target: Target,
+) -> Result<Option<InverterConfig>, Whatever> {
+    let mut inverter = build_inverter(dev, inverter_cfg);
+    let (bridge, connection_events) = match target {
+        Target::SelfTest(cmd) => return self_test(&mut inverter, cmd).map(|()| None),
+        Target::Socket(socket) => return daemon::listen(&mut inverter, socket).map(|()| None),
+        Target::Poll(bridge, connection_events) => (bridge, connection_events),
+    };
+    let mut reloaded: Option<InverterConfig> = None;
     loop {
-        // TODO: Take into account maximum packet size
-        if let Some((dev, _max_packet_size)) = dev {
-            let mut dev = dev.open()
-                .with_whatever_context(|e| format!("Cannot open USB device: {e}"))?;
-            dev.set_auto_detach_kernel_driver(true)
-               .with_whatever_context(|e| format!("Cannot detach USB kernel driver: {e}"))?;
-            dev.claim_interface(config.inverter.usb.interface)
-               .with_whatever_context(|e| format!("Cannot claim USB interface: {e}"))?;
-
-            let dev = InverterUSBDevice::new(
-                dev,
-                config.inverter.usb.request_params.clone(),
-                config.inverter.usb.response_params.clone()
-            );
-            let mut inverter = Inverter::new(dev);
-            let mqtt_client = establish_mqtt_conn(&config.mqtt)?;
-            return run(&mut inverter, &config.inverter, &mqtt_client);
-        } else {
-            log::warn!("Devices are not found. Waiting");
-            sleep(Duration::from_secs(INVERTER_RETRY_DELAY_SECS));
-            continue;
+        let current_cfg = reloaded.as_ref().unwrap_or(inverter_cfg);
+        let Some(new_cfg) = run(&mut inverter, current_cfg, bridge, connection_events)? else {
+            return Ok(None);
+        };
+        log::info!("Config of '{}' inverter is reloaded", new_cfg.id);
+        let new_topics = entity_topics(&new_cfg, bridge.mqtt_cfg);
+        for topic in entity_topics(current_cfg, bridge.mqtt_cfg) {
+            if !new_topics.contains(&topic) {
+                clear_retained(bridge.mqtt_client, &topic)?;
+            }
+        }
+        if new_cfg.device != inverter_cfg.device {
+            log::info!("Device of '{}' inverter is changed, reopening it", new_cfg.id);
+            return Ok(Some(new_cfg));
+        }
+        // Protocol settings may have changed
+        inverter = build_inverter(inverter.into_device(), &new_cfg);
+        reloaded = Some(new_cfg);
+    }
+}
+
+fn poll_inverter(
+    inverter_cfg: &InverterConfig,
+    bridge: &Bridge,
+    connection_events: &ConnectionEvents,
+) -> Result<(), Whatever> {
+    let mut reloaded = None;
+    loop {
+        let current_cfg = reloaded.as_ref().unwrap_or(inverter_cfg);
+        match open_and_serve(current_cfg, Target::Poll(bridge, connection_events))? {
+            Some(new_cfg) => reloaded = Some(new_cfg),
+            None => return Ok(()),
+        }
+    }
+}
+
+// Only the inverter sections are applied, other sections need a restart
+fn reload_inverter_config(bridge: &Bridge, id: &str) -> Result<InverterConfig, Whatever> {
+    let config = load_config(bridge.config_path, bridge.lenient)?;
+    let Some(inverter_cfg) = config.inverters.into_iter().find(|cfg| cfg.id == id) else {
+        whatever!("'{id}' inverter is not in the config anymore");
+    };
+    validate_inverter(&inverter_cfg)?;
+    Ok(inverter_cfg)
+}
+
+// Some with the new config when a reload was requested since the last check,
+// an invalid config is logged and the current one is kept
+fn requested_reload(
+    bridge: &Bridge,
+    id: &str,
+    seen_requests: &mut usize,
+) -> Option<InverterConfig> {
+    let requests = RELOAD_REQUESTS.load(Ordering::Relaxed);
+    if requests == *seen_requests {
+        return None;
+    }
+    *seen_requests = requests;
+    match reload_inverter_config(bridge, id) {
+        Ok(inverter_cfg) => Some(inverter_cfg),
+        Err(e) => {
+            log::error!("Cannot reload config of '{id}' inverter, keeping the current one: {e}");
+            None
         }
     }
 }
 
-fn establish_mqtt_conn(cfg: &MqttConfig) -> Result<mqtt::Client, Whatever> {
-    let client = mqtt::Client::new(format!("tcp://{}", cfg.address))
+// Every inverter is polled by its own thread, messages of the shared connection are passed
+// to all of them and the commands of other inverters are ignored
+fn poll_inverters(inverters: &[InverterConfig], bridge: &Bridge) -> Result<(), Whatever> {
+    let mqtt_client = bridge.mqtt_client;
+    let connection_events = mqtt_client.start_consuming();
+    let res = thread::scope(|scope| {
+        let mut senders = vec!();
+        let mut threads = vec!();
+        for inverter_cfg in inverters {
+            let (sender, receiver) = mpsc::channel();
+            senders.push(sender);
+            let thread = thread::Builder::new()
+                .name(inverter_cfg.id.clone())
+                .spawn_scoped(scope, move || {
+                    poll_inverter(inverter_cfg, bridge, &receiver)
+                        // Errors are not Send
+                        .map_err(|e| {
+                            // Other inverters stop as well, so the process exits with the error
+                            SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+                            format!("Polling '{}' inverter failed: {e}", inverter_cfg.id)
+                        })
+                });
+            match thread {
+                Ok(thread) => threads.push(thread),
+                Err(e) => {
+                    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+                    whatever!("Cannot start polling thread: {e}");
+                }
+            }
+        }
+
+        let mut is_online = None;
+        while threads.iter().any(|thread| !thread.is_finished()) {
+            if let Some(topic) = &bridge.availability_topic {
+                if is_online.is_none() && mqtt_client.is_connected() {
+                    match publish_availability(mqtt_client, topic, true) {
+                        Ok(()) => is_online = Some(true),
+                        Err(e) => log::warn!("Cannot publish availability: {e}"),
+                    }
+                }
+            }
+            match connection_events.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(event) => {
+                    if event.is_none() {
+                        is_online = None;
+                    }
+                    for sender in senders.iter() {
+                        // Receivers of finished threads are gone
+                        let _ = sender.send(event.clone());
+                    }
+                }
+                Err(e) if e.is_disconnected() => break,
+                Err(_) => {}
+            }
+        }
+        for thread in threads {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => whatever!("{e}"),
+                Err(_) => whatever!("Polling thread panicked"),
+            }
+        }
+        Ok(())
+    });
+    // The will message makes the entities unavailable when polling failed
+    res?;
+    if let Some(topic) = &bridge.availability_topic {
+        if let Err(e) = publish_availability(mqtt_client, topic, false) {
+            log::warn!("Cannot publish availability: {e}");
+        }
+    }
+    mqtt_client.disconnect(None)
+        .with_whatever_context(|e| format!("Cannot disconnect from mqtt server: {e}"))
+}
+
+fn handle_signals() -> Result<(), Whatever> {
+    for signal in [SIGINT, SIGTERM] {
+        // A second signal terminates at once when the shutdown hangs, e.g. on a blocking read.
+        // Registered first, so it sees the flag before the first signal sets it
+        signal_hook::flag::register_conditional_shutdown(signal, 1, SHUTDOWN_REQUESTED.clone())
+            .and_then(|_| signal_hook::flag::register(signal, SHUTDOWN_REQUESTED.clone()))
+            .with_whatever_context(|e| format!("Cannot handle signal {signal}: {e}"))?;
+    }
+    // SAFETY: the handler only updates an atomic which is async-signal-safe
+    let reload = unsafe {
+        signal_hook::low_level::register(SIGHUP, || {
+            RELOAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        })
+    };
+    reload.with_whatever_context(|e| format!("Cannot handle signal {SIGHUP}: {e}"))?;
+    Ok(())
+}
+
+// Wakes up early when shutdown or a reload is requested
+fn sleep_unless_interrupted(duration: Duration) {
+    let started_at = Instant::now();
+    let reload_requests = RELOAD_REQUESTS.load(Ordering::Relaxed);
+    while !SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+        && RELOAD_REQUESTS.load(Ordering::Relaxed) == reload_requests
+        && started_at.elapsed() < duration
+    {
+        sleep(duration.saturating_sub(started_at.elapsed()).min(SHUTDOWN_CHECK_INTERVAL));
+    }
+}
+
+fn self_test<T: InverterDevice>(inverter: &mut Inverter<T>, cmd: &str) -> Result<(), Whatever> {
+    let started_at = Instant::now();
+    match inverter.query(cmd) {
+        Ok(resp) => {
+            println!("Self-test passed in {:?}: '{cmd}' -> '{resp}'", started_at.elapsed());
+            Ok(())
+        }
+        Err(e) => whatever!("Self-test failed in {:?}: '{cmd}': {e}", started_at.elapsed()),
+    }
+}
+
+fn generate_config(vendor_id: u16, product_id: u16) -> Result<(), Whatever> {
+    let usb_cfg = generate::default_usb_config(vendor_id, product_id);
+    if find_usb_device(&usb_cfg)?.is_none() {
+        whatever!("Device {vendor_id:04x}:{product_id:04x} is not found");
+    }
+    let mut inverter = Inverter::builder(open_usb_device(&usb_cfg, None, None)?).build();
+    let mut responses = vec!();
+    for &cmd in generate::PROBE_COMMANDS {
+        match inverter.query(cmd) {
+            Ok(resp) => {
+                eprintln!("{cmd} -> '{resp}'");
+                responses.push((cmd, resp));
+            }
+            Err(e) => eprintln!("{cmd} failed: {e}"),
+        }
+    }
+    let responses = responses.iter()
+        .map(|(cmd, resp)| (*cmd, resp.as_str()))
+        .collect::<Vec<_>>();
+    print!("{}", generate::scaffold_config(&usb_cfg, &responses));
+    Ok(())
+}
+
+fn build_inverter<T: InverterDevice>(dev: T, cfg: &InverterConfig) -> Inverter<T> {
+    Inverter::builder(dev)
+        .max_response_bytes(cfg.max_response_bytes)
+        .decimal_comma(cfg.decimal_comma)
+        .padding(cfg.pad_byte, cfg.pad_length())
+        .address(cfg.address.clone())
+        .framing(cfg.framing.clone())
+        .crc(inverter_crc(cfg))
+        .min_command_interval(Duration::from_millis(cfg.min_command_interval_ms))
+        .build()
+}
+
+fn inverter_crc(cfg: &InverterConfig) -> &'static Crc<u16> {
+    match (&cfg.crc, cfg.crc_algorithm) {
+        (Some(crc), _) => custom_crc(crc),
+        (None, Some(algorithm)) => catalog_crc(algorithm),
+        (None, None) => &DEFAULT_CRC,
+    }
+}
+
+fn establish_mqtt_conn(
+    cfg: &MqttConfig,
+    inverters: &[InverterConfig],
+) -> Result<mqtt::Client, Whatever> {
+    let scheme = if cfg.tls.is_some() { "ssl" } else { "tcp" };
+    let create_opts = mqtt::CreateOptionsBuilder::new()
+        .server_uri(format!("{scheme}://{}", cfg.address))
+        .client_id(client_id(cfg, inverters))
+        .finalize();
+    let client = mqtt::Client::new(create_opts)
         .with_whatever_context(|e| format!("Error creating mqtt client: {e}"))?;
+    let query_interval_secs = inverters.iter()
+        .map(|inverter_cfg| inverter_cfg.query_interval_secs)
+        .max()
+        .unwrap_or_default();
+    // Broker makes the entities unavailable when the bridge dies
+    let will_topic = match inverters {
+        [inverter_cfg] => availability_topic(&inverter_base_topic(inverter_cfg, cfg)),
+        _ => bridge_availability_topic(cfg, inverters),
+    };
     let mut conn_opts_builder = mqtt::ConnectOptionsBuilder::new();
     conn_opts_builder
         .keep_alive_interval(
-            Duration::from_secs(INVERTER_QUERY_INTERVAL_SECS * 2)
+            Duration::from_secs(query_interval_secs * 2)
         )
         .automatic_reconnect(
             Duration::from_secs(MQTT_MIN_RETRY_INTERVAL_SECS),
             Duration::from_secs(MQTT_MAX_RETRY_INTERVAL_SECS)
         )
-        .clean_session(true);
+        .clean_session(true)
+        .will_message(mqtt::Message::new_retained(will_topic, AVAILABILITY_OFFLINE, 0));
     if let Some(auth) = &cfg.auth {
         conn_opts_builder
             .user_name(&auth.user)
             .password(&auth.password);
     }
+    if let Some(tls) = &cfg.tls {
+        conn_opts_builder.ssl_options(ssl_options(tls)?);
+    }
     let conn_opts = conn_opts_builder.finalize();
+    // A mismatched certificate and key is only detected by the TLS handshake
+    let has_client_cert = cfg.tls.as_ref().is_some_and(|tls| tls.client_cert.is_some());
 
+    let mut retry_limit = RetryLimit::new(cfg.max_retries);
     loop {
         if let Err(e) = client.connect(conn_opts.clone()) {
+            retry_limit.retry("connecting to mqtt server")?;
             log::warn!("Unable to connect to mqtt server. Waiting:\n\t{e}");
+            if has_client_cert {
+                log::warn!("Make sure the client certificate matches the client key");
+            }
             sleep(Duration::from_secs(MQTT_RETRY_DELAY_SECS));
         } else {
             return Ok(client);
@@ -170,113 +1052,1219 @@ fn establish_mqtt_conn(cfg: &MqttConfig) -> Result<mqtt::Client, Whatever> {
     }
 }
 
+fn ssl_options(tls: &MqttTls) -> Result<mqtt::SslOptions, Whatever> {
+    let mut ssl_opts_builder = mqtt::SslOptionsBuilder::new();
+    if let Some(ca_file) = &tls.ca_file {
+        check_pem_file(ca_file, "CERTIFICATE")?;
+        ssl_opts_builder.trust_store(ca_file)
+            .with_whatever_context(|e| format!("Invalid CA file path: {e}"))?;
+    }
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            check_pem_file(client_cert, "CERTIFICATE")?;
+            check_pem_file(client_key, "PRIVATE KEY")?;
+            ssl_opts_builder.key_store(client_cert)
+                .with_whatever_context(|e| format!("Invalid client certificate path: {e}"))?;
+            ssl_opts_builder.private_key(client_key)
+                .with_whatever_context(|e| format!("Invalid client key path: {e}"))?;
+        }
+        (None, None) => {}
+        _ => whatever!("Both client_cert and client_key must be set for TLS client authentication"),
+    }
+    if tls.insecure_skip_verify {
+        log::warn!("Server certificate of the mqtt broker is not verified");
+        ssl_opts_builder
+            .enable_server_cert_auth(false)
+            .verify(false);
+    }
+    Ok(ssl_opts_builder.finalize())
+}
+
+// Fails early with a clear message instead of an opaque TLS handshake error
+fn check_pem_file(path: &Path, label: &str) -> Result<(), Whatever> {
+    let content = std::fs::read_to_string(path)
+        .with_whatever_context(|e| format!("Cannot read {}: {e}", path.display()))?;
+    if !content.contains("-----BEGIN ") || !content.contains(&format!("{label}-----")) {
+        whatever!("{} does not contain a PEM encoded {}", path.display(), label.to_lowercase());
+    }
+    Ok(())
+}
+
+// Publishes a message waiting for the automatic reconnect when the client was disconnected
+fn publish(mqtt_client: &mqtt::Client, msg: mqtt::Message) -> Result<(), mqtt::Error> {
+    match mqtt_client.publish(msg.clone()) {
+        Err(e) if is_disconnected(&e) || !mqtt_client.is_connected() => {
+            log::warn!("Mqtt client is disconnected, waiting for reconnect: {e}");
+            let mut waited_secs = 0;
+            while !mqtt_client.is_connected() && waited_secs < MQTT_MAX_RETRY_INTERVAL_SECS {
+                sleep(Duration::from_secs(MQTT_MIN_RETRY_INTERVAL_SECS));
+                waited_secs += MQTT_MIN_RETRY_INTERVAL_SECS;
+            }
+            if !mqtt_client.is_connected() {
+                mqtt_client.reconnect()?;
+            }
+            mqtt_client.publish(msg)
+        }
+        res => res,
+    }
+}
+
+fn is_disconnected(err: &mqtt::Error) -> bool {
+    let mqtt::Error::Paho(disconnected) = mqtt::Disconnected else {
+        return false;
+    };
+    matches!(
+        err,
+        mqtt::Error::Paho(code) | mqtt::Error::PahoDescr(code, _) | mqtt::Error::Publish(code, _)
+            if *code == disconnected
+    )
+}
+
 fn create_entities(
     inverter_cfg: &InverterConfig,
+    mqtt_cfg: &MqttConfig,
     mqtt_client: &mqtt::Client,
     inverter_base_topic: &str,
+    shared_availability_topic: Option<&str>,
 ) -> Result<(), Whatever> {
-    for command in inverter_cfg.commands.iter() {
-        for sensor in command.sensors.iter().filter_map(|s| s.as_ref()) {
-            let entity_name = format!("{}_{}", inverter_cfg.id, sensor.name);
-            let discovery_name = sensor.human_name.clone()
-                .unwrap_or_else(||
-                    sensor.name.split('_').map(capitalize).collect::<Vec<_>>().join(" ")
-                );
-            let entity_base_topic = format!(
-                "{inverter_base_topic}/{entity_name}",
-            );
-            let entity_config_topic = format!("{entity_base_topic}/config");
-            let hass_discovery = hass::Discovery {
-                name: discovery_name,
-                object_id: entity_name.to_string(),
-                unique_id: entity_name.to_string(),
-                state_topic: format!("{entity_base_topic}/state"),
-                device: hass::Device {
-                    name: inverter_cfg.name.clone(),
-                    identifiers: vec![inverter_cfg.id.clone()],
-                    manufacturer: inverter_cfg.manufacturer.clone(),
-                    model: inverter_cfg.model.clone(),
-                },
-                device_class: sensor.device_class.to_string(),
-                unit_of_measurement: sensor.unit_of_measurement.to_string(),
-                icon: sensor.icon.to_string(),
-            };
-            let entity_msg = serde_json::to_string(&hass_discovery)
-                .with_whatever_context(|e| format!("Error when serializing discovery message: {e}"))?;
-            let discovery_msg = mqtt::Message::new_retained(
-                entity_config_topic.clone(),
-                entity_msg.clone(),
-                0
-            );
-            loop {
-                log::trace!("Sending message to {entity_config_topic}: {entity_msg}");
-                match mqtt_client.publish(discovery_msg.clone()) {
-                    Ok(()) => break,
-                    Err(e) => {
-                        log::warn!("Error when creating entity: {e}");
-                        sleep(Duration::from_secs(MQTT_RETRY_DELAY_SECS));
-                        continue;
-                    }
-                }
-            }
+    for sensor in inverter_cfg.sensors().filter(|s| !inverter_cfg.is_grouped(&s.name)) {
+        let entity_name = entity_name(inverter_cfg, &sensor.name);
+        let component = sensor_component(sensor);
+        let entity_base_topic = entity_base_topic(
+            inverter_cfg, mqtt_cfg, component, &sensor.name
+        );
+        // Binary sensors have neither units nor statistics
+        let is_binary = component == Component::BinarySensor;
+        let unit_of_measurement = if is_binary {
+            String::new()
+        } else {
+            unit_of_measurement(sensor)
+        };
+        let hass_discovery = hass::Discovery {
+            name: sensor.human_name.clone().unwrap_or_else(|| humanize(&sensor.name)),
+            object_id: sensor.object_id.clone().unwrap_or_else(|| entity_name.clone()),
+            unique_id: sensor.unique_id.clone().unwrap_or_else(|| entity_name.clone()),
+            // States of all sensors are published under the sensor component
+            state_topic: format!("{inverter_base_topic}/{entity_name}/state"),
+            availability: Some(sensor_availability(
+                inverter_cfg, sensor, inverter_base_topic, shared_availability_topic
+            )),
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, sensor.device.as_deref()),
+            device_class: sensor.device_class.to_string(),
+            unit_of_measurement,
+            icon: sensor.icon.to_string(),
+            entity_category: None,
+            state_class: sensor.state_class.filter(|_| !is_binary),
+            last_reset_value_template: sensor.last_reset_value_template.clone(),
+            value_template: sensor.value_template.clone(),
+            command_topic: None,
+            options: enum_options(sensor),
+        };
+        publish_discovery(mqtt_cfg, mqtt_client, component, &entity_base_topic, &hass_discovery)?;
+    }
+
+    for group in inverter_cfg.sensor_groups.iter() {
+        let Some(sensor) = group.sensors.first().and_then(|name| inverter_cfg.sensor(name)) else {
+            continue;
+        };
+        let entity_name = entity_name(inverter_cfg, &group.name);
+        let entity_base_topic = entity_base_topic(
+            inverter_cfg, mqtt_cfg, Component::Sensor, &group.name
+        );
+        let hass_discovery = hass::Discovery {
+            name: group.human_name.clone().unwrap_or_else(|| humanize(&group.name)),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{entity_base_topic}/state"),
+            availability: Some(hass::Availability::new(
+                availability_topic(inverter_base_topic), shared_availability_topic
+            )),
+            json_attributes_topic: Some(format!("{entity_base_topic}/attributes")),
+            device: hass_device(inverter_cfg, sensor.device.as_deref()),
+            device_class: sensor.device_class.to_string(),
+            unit_of_measurement: unit_of_measurement(sensor),
+            icon: sensor.icon.to_string(),
+            entity_category: None,
+            state_class: sensor.state_class,
+            last_reset_value_template: sensor.last_reset_value_template.clone(),
+            value_template: sensor.value_template.clone(),
+            command_topic: None,
+            options: Vec::new(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::Sensor, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    for (sensor, bit) in inverter_cfg.status_bits() {
+        let entity_name = entity_name(inverter_cfg, &bit.name);
+        let entity_base_topic = entity_base_topic(
+            inverter_cfg, mqtt_cfg, Component::BinarySensor, &bit.name
+        );
+        let hass_discovery = hass::Discovery {
+            name: bit.human_name.clone().unwrap_or_else(|| humanize(&bit.name)),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{inverter_base_topic}/{entity_name}/state"),
+            availability: Some(hass::Availability::new(
+                availability_topic(inverter_base_topic), shared_availability_topic
+            )),
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, sensor.device.as_deref()),
+            device_class: bit.device_class.to_string(),
+            unit_of_measurement: String::new(),
+            icon: bit.icon.to_string(),
+            entity_category: None,
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: Vec::new(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::BinarySensor, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    for setting in inverter_cfg.settings.iter() {
+        let entity_name = entity_name(inverter_cfg, &setting.name);
+        let entity_base_topic = entity_base_topic(
+            inverter_cfg, mqtt_cfg, Component::Select, &setting.name
+        );
+        let hass_discovery = hass::Discovery {
+            name: setting.human_name.clone().unwrap_or_else(|| humanize(&setting.name)),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{entity_base_topic}/state"),
+            availability: Some(hass::Availability::new(
+                availability_topic(inverter_base_topic), shared_availability_topic
+            )),
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, setting.device.as_deref()),
+            device_class: String::new(),
+            unit_of_measurement: String::new(),
+            icon: setting.icon.to_string(),
+            entity_category: Some(hass::EntityCategory::Config),
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: Some(format!("{entity_base_topic}/set")),
+            options: setting.options.iter().map(|o| o.name.clone()).collect(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::Select, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    for name in inverter_cfg.capture_extra_sensors() {
+        let entity_name = entity_name(inverter_cfg, name);
+        let entity_base_topic = entity_base_topic(inverter_cfg, mqtt_cfg, Component::Sensor, name);
+        let hass_discovery = hass::Discovery {
+            name: humanize(name),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{entity_base_topic}/state"),
+            availability: Some(hass::Availability::new(
+                availability_topic(inverter_base_topic), shared_availability_topic
+            )),
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, None),
+            device_class: String::new(),
+            unit_of_measurement: String::new(),
+            icon: String::new(),
+            entity_category: Some(hass::EntityCategory::Diagnostic),
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: Vec::new(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::Sensor, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    // Diagnostics stay available while the inverter is offline
+    for (name, _) in Diagnostics::default().values() {
+        let entity_name = entity_name(inverter_cfg, name);
+        let entity_base_topic = entity_base_topic(inverter_cfg, mqtt_cfg, Component::Sensor, name);
+        let hass_discovery = hass::Discovery {
+            name: humanize(name),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{entity_base_topic}/state"),
+            availability: None,
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, None),
+            device_class: String::new(),
+            unit_of_measurement: String::new(),
+            icon: DIAGNOSTICS_ICON.to_string(),
+            entity_category: Some(hass::EntityCategory::Diagnostic),
+            state_class: Some(StateClass::TotalIncreasing),
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: Vec::new(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::Sensor, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    if detects_baud_rate(inverter_cfg) {
+        let entity_name = entity_name(inverter_cfg, BAUD_RATE_SENSOR);
+        let entity_base_topic = entity_base_topic(
+            inverter_cfg, mqtt_cfg, Component::Sensor, BAUD_RATE_SENSOR
+        );
+        let hass_discovery = hass::Discovery {
+            name: humanize(BAUD_RATE_SENSOR),
+            object_id: entity_name.clone(),
+            unique_id: entity_name.clone(),
+            state_topic: format!("{entity_base_topic}/state"),
+            availability: None,
+            json_attributes_topic: None,
+            device: hass_device(inverter_cfg, None),
+            device_class: String::new(),
+            unit_of_measurement: "Bd".to_string(),
+            icon: "mdi:speedometer".to_string(),
+            entity_category: Some(hass::EntityCategory::Diagnostic),
+            state_class: None,
+            last_reset_value_template: None,
+            value_template: None,
+            command_topic: None,
+            options: Vec::new(),
+        };
+        publish_discovery(
+            mqtt_cfg, mqtt_client, Component::Sensor, &entity_base_topic, &hass_discovery
+        )?;
+    }
+
+    let entity_base_topic = entity_base_topic(
+        inverter_cfg, mqtt_cfg, Component::BinarySensor, CONNECTED_SENSOR
+    );
+    let entity_name = entity_name(inverter_cfg, CONNECTED_SENSOR);
+    let hass_discovery = hass::Discovery {
+        name: humanize(CONNECTED_SENSOR),
+        object_id: entity_name.clone(),
+        unique_id: entity_name,
+        state_topic: format!("{entity_base_topic}/state"),
+        availability: None,
+        json_attributes_topic: None,
+        device: hass_device(inverter_cfg, None),
+        device_class: "connectivity".to_string(),
+        unit_of_measurement: String::new(),
+        icon: String::new(),
+        entity_category: Some(hass::EntityCategory::Diagnostic),
+        state_class: None,
+        last_reset_value_template: None,
+        value_template: None,
+        command_topic: None,
+        options: Vec::new(),
+    };
+    publish_discovery(
+        mqtt_cfg, mqtt_client, Component::BinarySensor, &entity_base_topic, &hass_discovery
+    )?;
+
+    Ok(())
+}
+
+// Baud rate is published when it is detected among candidates
+fn detects_baud_rate(inverter_cfg: &InverterConfig) -> bool {
+    matches!(
+        &inverter_cfg.device,
+        DeviceConfig::Serial(cfg) if !cfg.candidate_baud_rates.is_empty()
+    )
+}
+
+fn sensor_component(sensor: &SensorConfig) -> Component {
+    match sensor.value_type {
+        ValueType::Boolean => Component::BinarySensor,
+        _ => Component::Sensor,
+    }
+}
+
+// Unit in the spelling Home Assistant converts, e.g. "volt" becomes "V"
+fn unit_of_measurement(sensor: &SensorConfig) -> String {
+    let unit = &sensor.unit_of_measurement;
+    match format::normalize_unit(&sensor.device_class, unit) {
+        format::Unit::Known(known) if known != unit => {
+            log::info!("Unit '{unit}' of '{}' sensor is normalized to '{known}'", sensor.name);
+            known.to_string()
         }
+        // Unknown units are rejected when the config is validated
+        _ => unit.clone(),
     }
+}
 
+// Removes all entities of the inverter from Home Assistant
+fn clear_discovery(
+    inverter_cfg: &InverterConfig,
+    mqtt_cfg: &MqttConfig,
+    mqtt_client: &mqtt::Client,
+) -> Result<(), Whatever> {
+    let inverter_base_topic = inverter_base_topic(inverter_cfg, mqtt_cfg);
+    let topics = entity_topics(inverter_cfg, mqtt_cfg).into_iter()
+        .chain([availability_topic(&inverter_base_topic), pause_state_topic(&inverter_base_topic)]);
+    for topic in topics {
+        clear_retained(mqtt_client, &topic)?;
+    }
     Ok(())
 }
 
-fn run<T: InverterDevice>(
+// Retained discovery, state and availability topics of every entity of the inverter
+fn entity_topics(inverter_cfg: &InverterConfig, mqtt_cfg: &MqttConfig) -> Vec<String> {
+    let inverter_base_topic = inverter_base_topic(inverter_cfg, mqtt_cfg);
+    let entities = inverter_cfg.sensors()
+        .map(|sensor| (sensor_component(sensor), sensor.name.as_str()))
+        .chain(inverter_cfg.sensor_groups.iter().map(|g| (Component::Sensor, g.name.as_str())))
+        .chain(inverter_cfg.status_bits().map(|(_, bit)| (Component::BinarySensor, &*bit.name)))
+        .chain(inverter_cfg.capture_extra_sensors().map(|name| (Component::Sensor, name)))
+        .chain(inverter_cfg.settings.iter().map(|s| (Component::Select, s.name.as_str())))
+        .chain(Diagnostics::default().values().map(|(name, _)| (Component::Sensor, name)))
+        .chain(detects_baud_rate(inverter_cfg).then_some((Component::Sensor, BAUD_RATE_SENSOR)))
+        .chain(std::iter::once((Component::BinarySensor, CONNECTED_SENSOR)));
+    entities
+        .map(|(component, name)| {
+            format!("{}/config", entity_base_topic(inverter_cfg, mqtt_cfg, component, name))
+        })
+        .chain(inverter_cfg.settings.iter().map(|setting| {
+            format!(
+                "{}/state",
+                entity_base_topic(inverter_cfg, mqtt_cfg, Component::Select, &setting.name)
+            )
+        }))
+        .chain(inverter_cfg.sensors().filter(|s| s.available_when.is_some()).map(|sensor| {
+            let entity_name = entity_name(inverter_cfg, &sensor.name);
+            entity_availability_topic(&inverter_base_topic, &entity_name)
+        }))
+        .collect()
+}
+
+fn clear_retained(mqtt_client: &mqtt::Client, topic: &str) -> Result<(), Whatever> {
+    log::info!("Clearing {topic}");
+    // Empty retained message deletes the retained one
+    publish(mqtt_client, mqtt::Message::new_retained(topic, Vec::<u8>::new(), 0))
+        .with_whatever_context(|e| format!("Cannot clear {topic}: {e}"))
+}
+
+// Forwards an option selected in Home Assistant to the inverter
+fn write_setting<T: InverterDevice>(
     inverter: &mut Inverter<T>,
     inverter_cfg: &InverterConfig,
+    mqtt_cfg: &MqttConfig,
     mqtt_client: &mqtt::Client,
+    setting: &SettingConfig,
+    payload: &str,
 ) -> Result<(), Whatever> {
-    let inverter_base_topic = format!(
-        "homeassistant/sensor/{}", &inverter_cfg.id
+    let Some(option) = setting.option(payload) else {
+        whatever!("Unknown option of '{}' setting: '{payload}'", setting.name);
+    };
+    let cmd = format!("{}{}", setting.command, option.argument);
+    inverter.write_command(&cmd)
+        .with_whatever_context(|e| format!("Cannot change '{}' setting: {e}", setting.name))?;
+    let confirmed = match &setting.read_back {
+        Some(sensor) if setting.verify_after_write => {
+            let value = read_back_setting(inverter, inverter_cfg, setting, sensor)?;
+            let Some(confirmed) = setting.option_by_value(&value) else {
+                whatever!("'{}' setting was read back as unknown '{value}'", setting.name);
+            };
+            if confirmed.name != option.name {
+                log::warn!(
+                    "Inverter acknowledged '{}' setting but kept '{}' instead of '{payload}'",
+                    setting.name, confirmed.name
+                );
+            }
+            confirmed
+        }
+        _ => option,
+    };
+    log::info!("'{}' setting is '{}'", setting.name, confirmed.name);
+    let state_topic = format!(
+        "{}/state",
+        entity_base_topic(inverter_cfg, mqtt_cfg, Component::Select, &setting.name)
     );
+    publish(mqtt_client, mqtt::Message::new_retained(state_topic, confirmed.name.as_str(), 0))
+        .with_whatever_context(|e| format!("Cannot publish '{}' setting state: {e}", setting.name))
+}
+
+// Current value of the setting according to the read back sensor
+fn read_back_setting<T: InverterDevice>(
+    inverter: &mut Inverter<T>,
+    inverter_cfg: &InverterConfig,
+    setting: &SettingConfig,
+    sensor: &str,
+) -> Result<String, Whatever> {
+    let Some(cmd) = read_back_command(inverter_cfg, sensor) else {
+        whatever!("'{}' setting has no command to read it back", setting.name);
+    };
+    let values = inverter.execute_command(cmd)
+        .with_whatever_context(|e| format!("Cannot read back '{}' setting: {e}", setting.name))?;
+    match format_sensor_values(&values).remove(sensor) {
+        Some(value) => Ok(value),
+        None => whatever!("'{}' setting was not read back, '{sensor}' is missing", setting.name),
+    }
+}
 
-    create_entities(inverter_cfg, &mqtt_client, &inverter_base_topic)?;
+fn read_back_command<'a>(
+    inverter_cfg: &'a InverterConfig,
+    sensor: &str,
+) -> Option<&'a CommandConfig> {
+    inverter_cfg.commands.iter()
+        .find(|cmd| cmd.sensors.iter().flatten().any(|s| s.name == sensor))
+}
 
+// Entities belong to the inverter unless assigned to one of its sub devices
+fn hass_device(inverter_cfg: &InverterConfig, sub_device: Option<&str>) -> hass::Device {
+    let Some(sub_device) = sub_device.and_then(|id| inverter_cfg.sub_device(id)) else {
+        return hass::Device {
+            name: inverter_cfg.name.clone(),
+            identifiers: vec![inverter_cfg.id.clone()],
+            manufacturer: inverter_cfg.manufacturer.clone(),
+            model: inverter_cfg.model.clone(),
+            via_device: None,
+        };
+    };
+    hass::Device {
+        name: sub_device.name.clone(),
+        identifiers: vec![format!("{}_{}", inverter_cfg.id, sub_device.id)],
+        manufacturer: sub_device.manufacturer.clone()
+            .unwrap_or_else(|| inverter_cfg.manufacturer.clone()),
+        model: sub_device.model.clone().unwrap_or_else(|| inverter_cfg.model.clone()),
+        via_device: Some(inverter_cfg.id.clone()),
+    }
+}
+
+fn publish_discovery(
+    mqtt_cfg: &MqttConfig,
+    mqtt_client: &mqtt::Client,
+    component: Component,
+    entity_base_topic: &str,
+    hass_discovery: &hass::Discovery,
+) -> Result<(), Whatever> {
+    let entity_config_topic = format!("{entity_base_topic}/config");
+    let entity_msg = hass_discovery.to_json(mqtt_cfg.discovery_format, entity_base_topic)
+        .with_whatever_context(|e| format!("Error when serializing discovery message: {e}"))?;
+    let opts = mqtt_cfg.discovery_options(component);
+    let discovery_msg = if opts.retain {
+        mqtt::Message::new_retained(entity_config_topic.clone(), entity_msg.clone(), opts.qos)
+    } else {
+        mqtt::Message::new(entity_config_topic.clone(), entity_msg.clone(), opts.qos)
+    };
+    let mut retry_limit = RetryLimit::new(mqtt_cfg.max_retries);
+    loop {
+        log::trace!("Sending message to {entity_config_topic}: {entity_msg}");
+        match publish(mqtt_client, discovery_msg.clone()) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                retry_limit.retry("creating entities")?;
+                log::warn!("Error when creating entity: {e}");
+                sleep(Duration::from_secs(MQTT_RETRY_DELAY_SECS));
+                continue;
+            }
+        }
+    }
+}
+
+// Lost connections are reported as none
+type ConnectionEvents = mpsc::Receiver<Option<mqtt::Message>>;
+
+// Some with the new config when it was reloaded on SIGHUP
+fn run<T: InverterDevice>(
+    inverter: &mut Inverter<T>,
+    inverter_cfg: &InverterConfig,
+    bridge: &Bridge,
+    connection_events: &ConnectionEvents,
+) -> Result<Option<InverterConfig>, Whatever> {
+    let mqtt_cfg = bridge.mqtt_cfg;
+    let mqtt_client = bridge.mqtt_client;
+    let shared_availability_topic = bridge.availability_topic.as_deref();
+    let inverter_base_topic = inverter_base_topic(inverter_cfg, mqtt_cfg);
+    let mut reload_requests = RELOAD_REQUESTS.load(Ordering::Relaxed);
+
+    if let Some(probe_cmd) = &inverter_cfg.probe_command {
+        probe(inverter, probe_cmd, inverter_cfg.max_retries)?;
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            return shut_down(mqtt_client, &availability_topic(&inverter_base_topic)).map(|()| None);
+        }
+    }
+    create_entities(
+        inverter_cfg, mqtt_cfg, mqtt_client, &inverter_base_topic, shared_availability_topic
+    )?;
+    let mut discovered_at = Instant::now();
+    let rediscovery_interval = inverter_cfg.rediscovery_interval_secs.map(Duration::from_secs);
+
+    let mut log_throttle = LogThrottle::new(Duration::from_secs(inverter_cfg.log_throttle_secs));
+    // Latest values of all sensors for evaluating availability conditions
+    let mut latest_values = HashMap::new();
+    // Published availability of the sensors with a condition
+    let mut sensors_availability = HashMap::new();
+    let availability_topic = availability_topic(&inverter_base_topic);
+    let connected_state_topic = format!(
+        "{}/state",
+        entity_base_topic(inverter_cfg, mqtt_cfg, Component::BinarySensor, CONNECTED_SENSOR),
+    );
+    let mut consecutive_failures = 0;
+    let mut cycle_retry_limit = RetryLimit::new(inverter_cfg.max_retries);
+    let mut is_online = None;
+    let pause_command_topic = format!("{inverter_base_topic}/pause/set");
+    let pause_state_topic = pause_state_topic(&inverter_base_topic);
+    let setting_command_topics = inverter_cfg.settings.iter()
+        .map(|setting| {
+            let base_topic = entity_base_topic(
+                inverter_cfg, mqtt_cfg, Component::Select, &setting.name
+            );
+            (format!("{base_topic}/set"), setting)
+        })
+        .collect::<Vec<_>>();
+    let command_topics = std::iter::once(pause_command_topic.clone())
+        .chain(setting_command_topics.iter().map(|(topic, _)| topic.clone()))
+        .collect::<Vec<_>>();
+    let mut subscribed = false;
+    let mut paused = false;
+    let mut is_paused = None;
+    let mut diagnostics = Diagnostics::default();
+    let mut derived_state = derived::DerivedState::default();
+    let mut aggregates = aggregate::Aggregates::default();
+    // Cycles follow the most frequently polled command
+    let cycle_interval = inverter_cfg.commands.iter()
+        .map(|cmd| command_interval(inverter_cfg, cmd))
+        .min()
+        .unwrap_or(Duration::from_secs(inverter_cfg.query_interval_secs));
+    let mut executed_at: Vec<Option<Instant>> = vec![None; inverter_cfg.commands.len()];
+    #[cfg(feature = "influxdb")]
+    let mut influxdb_writer = bridge.influxdb_cfg
+        .map(|cfg| influxdb::InfluxDbWriter::new(cfg, &inverter_cfg.id));
     loop {
-        for cmd_config in inverter_cfg.commands.iter() {
-            let sensors_data = match inverter.execute_command(&cmd_config) {
-                Ok(resp) => resp,
+        // Subscriptions of a clean session are lost with the connection
+        if !subscribed && mqtt_client.is_connected() {
+            match mqtt_client.subscribe_many(&command_topics, &vec![1; command_topics.len()]) {
+                Ok(_) => subscribed = true,
+                Err(e) => log_throttle.warn(
+                    "subscribe", &format!("Cannot subscribe to command topics: {e}")
+                ),
+            }
+        }
+        for event in connection_events.try_iter() {
+            let Some(msg) = event else {
+                // Will message replaced the availability while the client was disconnected
+                is_online = None;
+                subscribed = false;
+                continue;
+            };
+            let payload = msg.payload_str();
+            if msg.topic() == pause_command_topic {
+                match &*payload {
+                    BINARY_SENSOR_ON => paused = true,
+                    BINARY_SENSOR_OFF => paused = false,
+                    payload => log::warn!("Unknown pause command: '{payload}'"),
+                }
+                continue;
+            }
+            let Some((_, setting)) = setting_command_topics.iter().find(|(t, _)| t == msg.topic())
+            else {
+                continue;
+            };
+            if paused {
+                log::warn!("Ignoring '{}' setting while polling is paused", setting.name);
+                continue;
+            }
+            let res = write_setting(
+                inverter, inverter_cfg, mqtt_cfg, mqtt_client, setting, &payload
+            );
+            if let Err(e) = res {
+                log::warn!("{e}");
+            }
+        }
+        if is_paused != Some(paused) {
+            log::info!("Polling is {}", if paused { "paused" } else { "resumed" });
+            let payload = if paused { BINARY_SENSOR_ON } else { BINARY_SENSOR_OFF };
+            let msg = mqtt::Message::new_retained(&pause_state_topic, payload, 0);
+            match publish(mqtt_client, msg) {
+                Ok(()) => is_paused = Some(paused),
+                Err(e) => log_throttle.warn(
+                    "pause_state", &format!("Cannot publish pause state: {e}")
+                ),
+            }
+        }
+        if paused {
+            sleep_unless_interrupted(cycle_interval);
+            if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+                return shut_down(mqtt_client, &availability_topic).map(|()| None);
+            }
+            let reloaded = requested_reload(bridge, &inverter_cfg.id, &mut reload_requests);
+            if reloaded.is_some() {
+                return Ok(reloaded);
+            }
+            continue;
+        }
+
+        let mut cycle_succeeded = false;
+        // Values read during this cycle for resolving fallbacks
+        let mut cycle_values = HashMap::new();
+        let mut cycle_commands = vec!();
+        #[cfg(feature = "influxdb")]
+        let mut cycle_sensors_data = HashMap::new();
+        for (cmd_config, executed_at) in inverter_cfg.commands.iter().zip(executed_at.iter_mut()) {
+            // Stops between commands, so messages of a command are never published partially
+            if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+                return shut_down(mqtt_client, &availability_topic).map(|()| None);
+            }
+            let interval = command_interval(inverter_cfg, cmd_config);
+            if executed_at.is_some_and(|at| at.elapsed() < interval) {
+                continue;
+            }
+            *executed_at = Some(Instant::now());
+            cycle_commands.push(cmd_config);
+            let res = inverter.execute_command(cmd_config);
+            diagnostics.record(&res);
+            let crc_failures = diagnostics.consecutive_crc_failures();
+            let threshold = u64::from(inverter_cfg.crc_mismatch_threshold);
+            if threshold > 0 && crc_failures > 0 && crc_failures % threshold == 0 {
+                log::warn!(
+                    "{crc_failures} consecutive CRC failures, \
+                    possible baud rate or protocol mismatch"
+                );
+                if let Some(baud_rate) = inverter.next_baud_rate() {
+                    log::warn!("Trying {baud_rate} baud");
+                }
+            }
+            if inverter_cfg.publish_raw_hex && !inverter.last_frame().is_empty() {
+                let topic = format!("{inverter_base_topic}/raw/{}", cmd_config.full_command());
+                let msg = mqtt::Message::new(topic, format_hex(inverter.last_frame()), 0);
+                if let Err(e) = publish(mqtt_client, msg) {
+                    log_throttle.warn("raw_response", &format!("Cannot publish raw response: {e}"));
+                }
+            }
+            let mut sensors_data = match res {
+                Ok(resp) => {
+                    cycle_succeeded = true;
+                    resp
+                }
                 Err(e) => {
-                    log::warn!("Error when executing command '{}': {e}", cmd_config.command);
-                    sleep(Duration::from_secs(INVERTER_RETRY_DELAY_SECS));
+                    let cmd = cmd_config.full_command();
+                    let msg = format!("Error when executing command '{cmd}': {e}");
+                    log_throttle.warn(&format!("command:{cmd}"), &msg);
+                    sleep_unless_interrupted(Duration::from_secs(INVERTER_RETRY_DELAY_SECS));
                     continue;
                 }
             };
+            if cmd_config.is_ping() {
+                log::debug!("Command '{}' succeeded", cmd_config.full_command());
+                continue;
+            }
+            #[cfg(feature = "influxdb")]
+            if influxdb_writer.is_some() {
+                cycle_sensors_data.extend(sensors_data.clone());
+            }
+            // InfluxDB gets the raw samples
+            for sensor in cmd_config.sensors.iter().flatten() {
+                if let (Some(aggregate), Some(value)) =
+                    (&sensor.aggregate, sensors_data.remove(&sensor.name))
+                {
+                    let value = aggregates.add(sensor, aggregate, value);
+                    sensors_data.insert(sensor.name.clone(), value);
+                }
+            }
+            let entity_values = match &inverter_cfg.post_process_command {
+                Some(post_process_cmd) => {
+                    let timeout = Duration::from_millis(inverter_cfg.post_process_timeout_ms);
+                    match postprocess::post_process(post_process_cmd, &sensors_data, timeout) {
+                        Ok(values) => values,
+                        Err(e) => {
+                            log_throttle.warn(
+                                "post_process",
+                                &format!("Post-processing failed, publishing raw values: {e}"),
+                            );
+                            format_sensor_values(&sensors_data)
+                        }
+                    }
+                }
+                None => format_sensor_values(&sensors_data),
+            };
+            latest_values.extend(entity_values.clone());
+            cycle_values.extend(entity_values.clone());
             for sensor in cmd_config.sensors.iter().filter_map(|s| s.as_ref()) {
-                let sensor_value = match sensors_data.get(&sensor.name) {
-                    Some(v) => v,
+                let is_available = sensor.available_when.as_ref()
+                    .is_none_or(|cond| cond.is_met(&latest_values));
+                let entity_value = match entity_values.get(&sensor.name) {
+                    Some(v) => format::format_value(&sensor.device_class, v),
+                    // Resolved after all commands are executed
+                    None if sensor.fallback.is_some() => continue,
+                    None if cmd_config.on_short_response == ShortResponsePolicy::Unavailable => {
+                        UNAVAILABLE_STATE.to_string()
+                    }
                     None => {
-                        log::warn!("Missing value for sensor: {}", &sensor.name);
+                        let key = format!("missing_value:{}", sensor.name);
+                        let msg = format!("Missing value for sensor: {}", &sensor.name);
+                        log_throttle.warn(&key, &msg);
                         continue;
                     }
                 };
-                let entity_name = format!("{}_{}", &inverter_cfg.id, &sensor.name);
-                let entity_value = match sensor_value {
-                    SensorValue::Integer(v) => format!("{v}"),
-                    SensorValue::Float(v) => format!("{v}"),
-                    SensorValue::String(v) => v.clone(),
-                };
-                let entity_state_topic = format!("{inverter_base_topic}/{entity_name}/state");
-                let entity_state_msg = mqtt::Message::new(
-                    entity_state_topic.clone(),
-                    entity_value.clone(),
-                    0
+                if let Err(e) = publish_sensor_availability(
+                    mqtt_client, &inverter_base_topic, inverter_cfg, sensor, is_available,
+                    &mut sensors_availability,
+                ) {
+                    log_throttle.warn(
+                        "entity_availability", &format!("Cannot publish entity availability: {e}")
+                    );
+                }
+                if !is_available {
+                    continue;
+                }
+                if let Err(e) = publish_state(
+                    mqtt_client, mqtt_cfg, &inverter_base_topic, inverter_cfg, sensor, &entity_value
+                ) {
+                    log_throttle.warn("entity_state", &format!("Cannot publish entity state: {e}"));
+                    // Skip the rest of the sensors only if the reconnect did not help
+                    if !mqtt_client.is_connected() {
+                        break;
+                    }
+                }
+            }
+            let extra = cmd_config.capture_extra.as_ref()
+                .and_then(|name| Some((name, entity_values.get(name)?)));
+            if let Some((name, value)) = extra {
+                let entity_name = entity_name(inverter_cfg, name);
+                let topic = format!("{inverter_base_topic}/{entity_name}/state");
+                let msg = state_message(mqtt_cfg, topic, value.as_str());
+                if let Err(e) = publish(mqtt_client, msg) {
+                    log_throttle.warn("extra_fields", &format!("Cannot publish extra fields: {e}"));
+                }
+            }
+            let bits = cmd_config.sensors.iter().flatten()
+                .flat_map(|s| s.bits.iter().flatten())
+                .filter_map(|bit| Some((&bit.name, entity_values.get(&bit.name)?)));
+            for (name, value) in bits {
+                let entity_name = entity_name(inverter_cfg, name);
+                let topic = format!("{inverter_base_topic}/{entity_name}/state");
+                let msg = state_message(mqtt_cfg, topic, value.as_str());
+                if let Err(e) = publish(mqtt_client, msg) {
+                    log_throttle.warn("status_bit", &format!("Cannot publish status bit: {e}"));
+                }
+            }
+        }
+
+        if inverter_cfg.reopen_each_cycle {
+            inverter.release_device();
+        }
+
+        let fallback_sensors = cycle_commands.iter()
+            .flat_map(|cmd| cmd.sensors.iter().filter_map(|s| s.as_ref()))
+            .filter(|sensor| !cycle_values.contains_key(&sensor.name));
+        for sensor in fallback_sensors {
+            let Some(fallback) = &sensor.fallback else {
+                continue;
+            };
+            let Some(value) = cycle_values.get(fallback) else {
+                let key = format!("missing_value:{}", sensor.name);
+                log_throttle.warn(&key, &format!("Missing value for sensor: {}", &sensor.name));
+                continue;
+            };
+            log::debug!("Using '{fallback}' value for '{}' sensor", &sensor.name);
+            latest_values.insert(sensor.name.clone(), value.clone());
+            let is_available = sensor.available_when.as_ref()
+                .is_none_or(|cond| cond.is_met(&latest_values));
+            if let Err(e) = publish_sensor_availability(
+                mqtt_client, &inverter_base_topic, inverter_cfg, sensor, is_available,
+                &mut sensors_availability,
+            ) {
+                log_throttle.warn(
+                    "entity_availability", &format!("Cannot publish entity availability: {e}")
                 );
+            }
+            if !is_available {
+                continue;
+            }
+            let entity_value = format::format_value(&sensor.device_class, value);
+            if let Err(e) = publish_state(
+                mqtt_client, mqtt_cfg, &inverter_base_topic, inverter_cfg, sensor, &entity_value
+            ) {
+                log_throttle.warn("entity_state", &format!("Cannot publish entity state: {e}"));
+            }
+        }
 
-                log::trace!("Sending message to {entity_state_topic}: {entity_value}");
-                if let Err(e) = mqtt_client.publish(entity_state_msg) {
-                    log::warn!("Cannot publish entity state: {e}");
-                    break;
-                }
+        for derived in inverter_cfg.derived_sensors.iter() {
+            let sensor = &derived.sensor;
+            let Some(value) = derived_state.evaluate(derived, &latest_values, Instant::now()) else {
+                continue;
+            };
+            latest_values.insert(sensor.name.clone(), value.clone());
+            let is_available = sensor.available_when.as_ref()
+                .is_none_or(|cond| cond.is_met(&latest_values));
+            if let Err(e) = publish_sensor_availability(
+                mqtt_client, &inverter_base_topic, inverter_cfg, sensor, is_available,
+                &mut sensors_availability,
+            ) {
+                log_throttle.warn(
+                    "entity_availability", &format!("Cannot publish entity availability: {e}")
+                );
+            }
+            if !is_available {
+                continue;
+            }
+            if let Err(e) = publish_state(
+                mqtt_client, mqtt_cfg, &inverter_base_topic, inverter_cfg, sensor, &value
+            ) {
+                log_throttle.warn("entity_state", &format!("Cannot publish entity state: {e}"));
+            }
+        }
+
+        for group in inverter_cfg.sensor_groups.iter() {
+            let Some((state, attributes)) = group_state(inverter_cfg, group, &latest_values) else {
+                continue;
+            };
+            let entity_base_topic = format!(
+                "{inverter_base_topic}/{}", entity_name(inverter_cfg, &group.name)
+            );
+            let messages = [
+                state_message(mqtt_cfg, format!("{entity_base_topic}/state"), state),
+                state_message(mqtt_cfg, format!("{entity_base_topic}/attributes"), attributes),
+            ];
+            if let Err(e) = messages.into_iter().try_for_each(|msg| publish(mqtt_client, msg)) {
+                log_throttle.warn("sensor_group", &format!("Cannot publish sensor group: {e}"));
+            }
+        }
+
+        if let Some(snapshot_file) = &inverter_cfg.snapshot_file {
+            if let Err(e) = snapshot::write_snapshot(snapshot_file, &latest_values) {
+                log_throttle.warn("snapshot", &e.to_string());
+            }
+        }
+
+        #[cfg(feature = "influxdb")]
+        if let Some(writer) = &mut influxdb_writer {
+            if let Err(e) = writer.write(&cycle_sensors_data) {
+                log_throttle.warn("influxdb", &e.to_string());
+            }
+        }
+
+        for (name, value) in diagnostics.values() {
+            let topic = format!("{inverter_base_topic}/{}/state", entity_name(inverter_cfg, name));
+            let msg = state_message(mqtt_cfg, topic, value.to_string());
+            if let Err(e) = publish(mqtt_client, msg) {
+                log_throttle.warn("diagnostics", &format!("Cannot publish diagnostics: {e}"));
+                break;
+            }
+        }
+        let baud_rate = inverter.baud_rate().filter(|_| detects_baud_rate(inverter_cfg));
+        if let Some(baud_rate) = baud_rate.filter(|_| cycle_succeeded) {
+            let entity_name = entity_name(inverter_cfg, BAUD_RATE_SENSOR);
+            let topic = format!("{inverter_base_topic}/{entity_name}/state");
+            let msg = state_message(mqtt_cfg, topic, baud_rate.to_string());
+            if let Err(e) = publish(mqtt_client, msg) {
+                log_throttle.warn("baud_rate", &format!("Cannot publish baud rate: {e}"));
+            }
+        }
+
+        // Single missed cycles should not make entities unavailable
+        let online = if cycle_succeeded {
+            consecutive_failures = 0;
+            cycle_retry_limit.reset();
+            Some(true)
+        } else {
+            if let Err(e) = cycle_retry_limit.retry("polling the inverter") {
+                shut_down(mqtt_client, &availability_topic)?;
+                return Err(e);
+            }
+            consecutive_failures += 1;
+            (consecutive_failures >= inverter_cfg.unavailable_after_failures).then_some(false)
+        };
+        // Unlike availability the connectivity is published every cycle to be graphed
+        let connected = consecutive_failures < inverter_cfg.unavailable_after_failures;
+        let payload = if connected { BINARY_SENSOR_ON } else { BINARY_SENSOR_OFF };
+        let connected_msg = state_message(mqtt_cfg, &connected_state_topic, payload);
+        if let Err(e) = publish(mqtt_client, connected_msg) {
+            log_throttle.warn("connectivity", &format!("Cannot publish connectivity: {e}"));
+        }
+        if let Some(online) = online.filter(|&online| is_online != Some(online)) {
+            match publish_availability(mqtt_client, &availability_topic, online) {
+                Ok(()) => is_online = Some(online),
+                Err(e) => log_throttle.warn(
+                    "availability", &format!("Cannot publish availability: {e}")
+                ),
+            }
+        }
+
+        if rediscovery_interval.is_some_and(|interval| discovered_at.elapsed() >= interval) {
+            log::debug!("Re-sending discovery messages");
+            create_entities(
+                inverter_cfg, mqtt_cfg, mqtt_client, &inverter_base_topic, shared_availability_topic
+            )?;
+            discovered_at = Instant::now();
+        }
+
+        sleep_unless_interrupted(cycle_interval);
+        if SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+            return shut_down(mqtt_client, &availability_topic).map(|()| None);
+        }
+        let reloaded = requested_reload(bridge, &inverter_cfg.id, &mut reload_requests);
+        if reloaded.is_some() {
+            return Ok(reloaded);
+        }
+    }
+}
+
+// Waits until the inverter answers with a valid frame, so entities of a dead device
+// are not discovered
+fn probe<T: InverterDevice>(
+    inverter: &mut Inverter<T>,
+    probe_cmd: &str,
+    max_retries: Option<u32>,
+) -> Result<(), Whatever> {
+    let mut delay = Duration::from_secs(INVERTER_RETRY_DELAY_SECS);
+    let mut retry_limit = RetryLimit::new(max_retries);
+    while !SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+        match inverter.query_unit(probe_cmd) {
+            Ok(resp) => {
+                log::info!("Inverter answered '{probe_cmd}' probe: '{resp}'");
+                return Ok(());
+            }
+            Err(e) => {
+                retry_limit.retry(&format!("probing the inverter with '{probe_cmd}'"))?;
+                log::warn!("Inverter did not answer '{probe_cmd}' probe, retry in {delay:?}: {e}");
             }
         }
+        sleep_unless_interrupted(delay);
+        delay = (delay * 2).min(Duration::from_secs(PROBE_MAX_DELAY_SECS));
+    }
+    Ok(())
+}
+
+// The shared connection is closed once all inverters are shut down
+fn shut_down(mqtt_client: &mqtt::Client, availability_topic: &str) -> Result<(), Whatever> {
+    log::info!("Shutting down");
+    if let Err(e) = publish_availability(mqtt_client, availability_topic, false) {
+        log::warn!("Cannot publish availability: {e}");
+    }
+    Ok(())
+}
 
-        sleep(Duration::from_secs(INVERTER_QUERY_INTERVAL_SECS));
+// State of the first sensor and a JSON object with formatted values of all sensors,
+// none until the first sensor has a value
+fn group_state(
+    inverter_cfg: &InverterConfig,
+    group: &SensorGroupConfig,
+    latest_values: &HashMap<String, String>,
+) -> Option<(String, String)> {
+    let mut state = None;
+    let mut attributes = serde_json::Map::new();
+    for (i, name) in group.sensors.iter().enumerate() {
+        let Some(value) = latest_values.get(name) else {
+            continue;
+        };
+        let Some(sensor) = inverter_cfg.sensor(name) else {
+            continue;
+        };
+        let is_available = sensor.available_when.as_ref()
+            .is_none_or(|cond| cond.is_met(latest_values));
+        let value = if is_available {
+            format::format_value(&sensor.device_class, value)
+        } else {
+            UNAVAILABLE_STATE.to_string()
+        };
+        if i == 0 {
+            state = Some(value.clone());
+        }
+        attributes.insert(sensor.name.clone(), serde_json::Value::String(value));
     }
+    Some((state?, serde_json::Value::Object(attributes).to_string()))
+}
+
+fn command_interval(inverter_cfg: &InverterConfig, cmd_config: &CommandConfig) -> Duration {
+    Duration::from_secs(cmd_config.interval_secs.unwrap_or(inverter_cfg.query_interval_secs))
+}
+
+// Publishes the state to the primary state topic and the extra ones
+fn publish_state(
+    mqtt_client: &mqtt::Client,
+    mqtt_cfg: &MqttConfig,
+    inverter_base_topic: &str,
+    inverter_cfg: &InverterConfig,
+    sensor: &SensorConfig,
+    entity_value: &str,
+) -> Result<(), mqtt::Error> {
+    let entity_name = entity_name(inverter_cfg, &sensor.name);
+    let entity_state_topic = format!("{inverter_base_topic}/{entity_name}/state");
+    // Discovery only refers to the primary state topic
+    let state_topics = std::iter::once(entity_state_topic)
+        .chain(sensor.extra_state_topics.iter().map(|topic| prefixed_topic(mqtt_cfg, topic)));
+    for state_topic in state_topics {
+        log::trace!("Sending message to {state_topic}: {entity_value}");
+        publish(mqtt_client, state_message(mqtt_cfg, state_topic, entity_value))?;
+    }
+    Ok(())
+}
+
+fn state_message(
+    mqtt_cfg: &MqttConfig,
+    topic: impl Into<String>,
+    payload: impl Into<Vec<u8>>,
+) -> mqtt::Message {
+    if mqtt_cfg.state_retain {
+        mqtt::Message::new_retained(topic, payload, mqtt_cfg.state_qos)
+    } else {
+        mqtt::Message::new(topic, payload, mqtt_cfg.state_qos)
+    }
+}
+
+fn inverter_base_topic(inverter_cfg: &InverterConfig, mqtt_cfg: &MqttConfig) -> String {
+    component_base_topic(inverter_cfg, mqtt_cfg, Component::Sensor)
+}
+
+fn component_base_topic(
+    inverter_cfg: &InverterConfig,
+    mqtt_cfg: &MqttConfig,
+    component: Component,
+) -> String {
+    prefixed_topic(
+        mqtt_cfg,
+        &format!("homeassistant/{}/{}", component.as_str(), &inverter_cfg.id),
+    )
+}
+
+fn entity_base_topic(
+    inverter_cfg: &InverterConfig,
+    mqtt_cfg: &MqttConfig,
+    component: Component,
+    name: &str,
+) -> String {
+    format!(
+        "{}/{}",
+        component_base_topic(inverter_cfg, mqtt_cfg, component),
+        entity_name(inverter_cfg, name),
+    )
+}
+
+fn prefixed_topic(mqtt_cfg: &MqttConfig, topic: &str) -> String {
+    match mqtt_cfg.topic_prefix.trim_end_matches('/') {
+        "" => topic.to_string(),
+        prefix => format!("{prefix}/{topic}"),
+    }
+}
+
+fn entity_name(inverter_cfg: &InverterConfig, sensor_name: &str) -> String {
+    format!("{}_{sensor_name}", &inverter_cfg.id)
+}
+
+// Ids of all inverters sharing the connection
+fn client_id(mqtt_cfg: &MqttConfig, inverters: &[InverterConfig]) -> String {
+    let ids = inverters.iter()
+        .map(|inverter_cfg| inverter_cfg.id.as_str())
+        .collect::<Vec<_>>();
+    mqtt_cfg.client_id(&ids.join("_"))
+}
+
+fn bridge_availability_topic(mqtt_cfg: &MqttConfig, inverters: &[InverterConfig]) -> String {
+    prefixed_topic(
+        mqtt_cfg, &format!("inverter2mqtt/{}/availability", client_id(mqtt_cfg, inverters))
+    )
+}
+
+// A single inverter has the will message on its own availability topic
+fn shared_availability_topic(
+    mqtt_cfg: &MqttConfig,
+    inverters: &[InverterConfig],
+) -> Option<String> {
+    (inverters.len() > 1).then(|| bridge_availability_topic(mqtt_cfg, inverters))
+}
+
+fn availability_topic(inverter_base_topic: &str) -> String {
+    format!("{inverter_base_topic}/availability")
+}
+
+fn entity_availability_topic(inverter_base_topic: &str, entity_name: &str) -> String {
+    format!("{inverter_base_topic}/{entity_name}/availability")
+}
+
+// Sensors with a condition are also unavailable while it is not met
+fn sensor_availability(
+    inverter_cfg: &InverterConfig,
+    sensor: &SensorConfig,
+    inverter_base_topic: &str,
+    shared_availability_topic: Option<&str>,
+) -> hass::Availability {
+    let topic = availability_topic(inverter_base_topic);
+    if sensor.available_when.is_none() {
+        return hass::Availability::new(topic, shared_availability_topic);
+    }
+    let entity_topic = entity_availability_topic(
+        inverter_base_topic, &entity_name(inverter_cfg, &sensor.name)
+    );
+    hass::Availability::all(
+        std::iter::once(topic)
+            .chain(shared_availability_topic.map(str::to_string))
+            .chain(std::iter::once(entity_topic))
+            .collect()
+    )
+}
+
+// Published only when the condition of the sensor changes, the topic is retained
+fn publish_sensor_availability(
+    mqtt_client: &mqtt::Client,
+    inverter_base_topic: &str,
+    inverter_cfg: &InverterConfig,
+    sensor: &SensorConfig,
+    is_available: bool,
+    published: &mut HashMap<String, bool>,
+) -> Result<(), mqtt::Error> {
+    if sensor.available_when.is_none() || published.get(&sensor.name) == Some(&is_available) {
+        return Ok(());
+    }
+    let topic = entity_availability_topic(
+        inverter_base_topic, &entity_name(inverter_cfg, &sensor.name)
+    );
+    let payload = if is_available { AVAILABILITY_ONLINE } else { AVAILABILITY_OFFLINE };
+    log::debug!("'{}' sensor is {payload}", sensor.name);
+    publish(mqtt_client, mqtt::Message::new_retained(topic, payload, 0))?;
+    published.insert(sensor.name.clone(), is_available);
+    Ok(())
+}
+
+fn pause_state_topic(inverter_base_topic: &str) -> String {
+    format!("{inverter_base_topic}/pause/state")
+}
+
+fn publish_availability(
+    mqtt_client: &mqtt::Client,
+    availability_topic: &str,
+    online: bool,
+) -> Result<(), mqtt::Error> {
+    let payload = if online { AVAILABILITY_ONLINE } else { AVAILABILITY_OFFLINE };
+    log::info!("Inverter is {payload}");
+    publish(mqtt_client, mqtt::Message::new_retained(availability_topic, payload, 0))
+}
+
+fn format_sensor_values(sensors_data: &HashMap<String, SensorValue>) -> HashMap<String, String> {
+    sensors_data.iter()
+        .map(|(name, value)| {
+            let value = match value {
+                SensorValue::Integer(v) => format!("{v}"),
+                SensorValue::Float(v) => format!("{v}"),
+                SensorValue::String(v) => v.clone(),
+                SensorValue::Boolean(v) => {
+                    if *v { BINARY_SENSOR_ON } else { BINARY_SENSOR_OFF }.to_string()
+                }
+                SensorValue::Unavailable => UNAVAILABLE_STATE.to_string(),
+            };
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+// Home Assistant lists the states of enum sensors as options
+fn enum_options(sensor: &SensorConfig) -> Vec<String> {
+    if sensor.device_class != "enum" {
+        return Vec::new();
+    }
+    let mut options = sensor.map.values().cloned().collect::<Vec<_>>();
+    options.sort();
+    options.dedup();
+    options
+}
+
+fn humanize(name: &str) -> String {
+    name.split('_').map(capitalize).collect::<Vec<_>>().join(" ")
 }
 
 fn capitalize(s: &str) -> String {
@@ -0,0 +1,626 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::num::{ParseFloatError, ParseIntError};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, SecondsFormat};
+use snafu::Snafu;
+use snafu::prelude::*;
+
+use crate::config::{
+    BinaryField,
+    CommandConfig,
+    Endian,
+    FieldDecoder,
+    FixedWidthConfig,
+    SensorConfig,
+    ShortResponsePolicy,
+    ValueType,
+};
+use crate::inverter::SensorValue;
+
+#[derive(Debug, PartialEq, Snafu)]
+pub enum ParseResponseError {
+    #[snafu(display("Expected float value for '{sensor}' sensor: {source}"))]
+    ExpectedFloat { sensor: String, source: ParseFloatError },
+
+    #[snafu(display("Expected integer value for '{sensor}' sensor: {source}"))]
+    ExpectedInteger { sensor: String, source: ParseIntError },
+
+    #[snafu(display("Expected boolean value for '{sensor}' sensor, got '{value}'"))]
+    ExpectedBoolean { sensor: String, value: String },
+
+    #[snafu(display("Expected {expected} fields but response has {actual}"))]
+    FieldCountMismatch { expected: usize, actual: usize },
+
+    #[snafu(display("Field {field} needs {needed} characters but only {available} are left"))]
+    FieldWidthOverflow { field: usize, needed: usize, available: usize },
+
+    #[snafu(display("Missing binary field for '{sensor}' sensor"))]
+    MissingBinaryField { sensor: String },
+
+    #[snafu(display(
+        "Binary field of '{sensor}' sensor at {offset}..{end} is out of {len} response bytes"
+    ))]
+    FieldOutOfRange { sensor: String, offset: usize, end: usize, len: usize },
+
+    #[snafu(display("Invalid BCD value for '{sensor}' sensor: {data:02x?}"))]
+    InvalidBcd { sensor: String, data: Vec<u8> },
+
+    #[snafu(display("Header has no field for '{sensor}' sensor"))]
+    MissingHeaderField { sensor: String },
+}
+
+// Splits the response on whitespace and parses tokens in the order of sensors
+pub fn parse_whitespace(
+    cfg: &CommandConfig,
+    data: &str,
+    decimal_comma: bool,
+) -> Result<HashMap<String, SensorValue>, ParseResponseError> {
+    let data = drop_trailing_fields(data, cfg.trailing_fields_to_drop);
+    let mut sensors_data = HashMap::new();
+    let mut extra = vec!();
+    match &cfg.section_separator {
+        Some(separator) => {
+            let mut sections = data.split(separator.as_str());
+            let mut sensors = cfg.sensors.as_slice();
+            for (i, &size) in cfg.section_sizes.iter().enumerate() {
+                let (section_sensors, rest) = sensors.split_at(size.min(sensors.len()));
+                sensors = rest;
+                // Missing sections are treated as empty ones
+                let values = sections.next().unwrap_or_default().split_ascii_whitespace();
+                let skip = if i == 0 { cfg.skip_leading_fields } else { 0 };
+                extra.extend(parse_fields(
+                    cfg, section_sensors, values.skip(skip), decimal_comma, &mut sensors_data
+                )?);
+            }
+            extra.extend(sections.flat_map(str::split_ascii_whitespace));
+        }
+        None => {
+            let values = data.split_ascii_whitespace().skip(cfg.skip_leading_fields);
+            extra = parse_fields(cfg, &cfg.sensors, values, decimal_comma, &mut sensors_data)?;
+        }
+    }
+    // Newer firmwares may append fields that are not described yet
+    if !extra.is_empty() {
+        log::debug!("Unconsumed fields of '{}' response: {extra:?}", cfg.full_command());
+    }
+    if let Some(name) = &cfg.capture_extra {
+        sensors_data.insert(name.clone(), SensorValue::String(extra.join(" ")));
+    }
+    Ok(sensors_data)
+}
+
+// Cuts off the last whitespace separated fields of the response
+fn drop_trailing_fields(data: &str, count: usize) -> &str {
+    let mut data = data.trim_end();
+    for _ in 0..count {
+        data = data.trim_end_matches(|c: char| !c.is_ascii_whitespace()).trim_end();
+    }
+    data
+}
+
+// Parses tokens into the sensors and returns the tokens left after them
+fn parse_fields<'a>(
+    cfg: &CommandConfig,
+    sensors: &[Option<SensorConfig>],
+    mut values: impl Iterator<Item = &'a str> + Clone,
+    decimal_comma: bool,
+    sensors_data: &mut HashMap<String, SensorValue>,
+) -> Result<Vec<&'a str>, ParseResponseError> {
+    if cfg.on_short_response == ShortResponsePolicy::Error {
+        let actual = values.clone().count();
+        ensure!(
+            actual >= sensors.len(),
+            FieldCountMismatchSnafu { expected: sensors.len(), actual }
+        );
+    }
+    for (sensor, value) in sensors.iter().zip(values.by_ref()) {
+        if let Some(sensor) = sensor {
+            let value = parse_value(sensor, value, decimal_comma)?;
+            sensors_data.insert(sensor.name.clone(), value);
+        }
+    }
+    Ok(values.collect())
+}
+
+// Matches sensors to the whitespace separated values by the field names of the header
+pub fn parse_header(
+    cfg: &CommandConfig,
+    header: &str,
+    data: &str,
+    decimal_comma: bool,
+) -> Result<HashMap<String, SensorValue>, ParseResponseError> {
+    let fields = header.split_ascii_whitespace()
+        .zip(data.split_ascii_whitespace())
+        .collect::<Vec<_>>();
+    let mut sensors_data = HashMap::new();
+    for sensor in cfg.sensors.iter().flatten() {
+        let value = fields.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&sensor.name))
+            .map(|&(_, value)| value);
+        let Some(value) = value else {
+            ensure!(
+                cfg.on_short_response != ShortResponsePolicy::Error,
+                MissingHeaderFieldSnafu { sensor: sensor.name.clone() }
+            );
+            continue;
+        };
+        sensors_data.insert(sensor.name.clone(), parse_value(sensor, value, decimal_comma)?);
+    }
+    Ok(sensors_data)
+}
+
+// Cuts the response into fields of configured widths, numbers may be padded with spaces
+pub fn parse_fixed_width(
+    cfg: &CommandConfig,
+    fixed_width: &FixedWidthConfig,
+    data: &str,
+    decimal_comma: bool,
+) -> Result<HashMap<String, SensorValue>, ParseResponseError> {
+    let mut sensors_data = HashMap::new();
+    let mut offset = 0;
+    for (i, (sensor, &width)) in cfg.sensors.iter().zip(fixed_width.widths.iter()).enumerate() {
+        let Some(value) = data.get(offset..offset + width) else {
+            ensure!(
+                cfg.on_short_response != ShortResponsePolicy::Error,
+                FieldWidthOverflowSnafu {
+                    field: i,
+                    needed: width,
+                    available: data.len().saturating_sub(offset),
+                }
+            );
+            break;
+        };
+        offset += width;
+        if let Some(sensor) = sensor {
+            let value = match sensor.value_type {
+                ValueType::String => value,
+                _ => value.trim(),
+            };
+            sensors_data.insert(sensor.name.clone(), parse_value(sensor, value, decimal_comma)?);
+        }
+    }
+    // Newer firmwares may append fields that are not described yet
+    if let Some(trailing) = data.get(offset..).filter(|trailing| !trailing.is_empty()) {
+        log::debug!("Unconsumed characters of '{}' response: '{trailing}'", cfg.full_command());
+    }
+    Ok(sensors_data)
+}
+
+// Decodes every sensor from its own binary field of the response
+pub fn parse_binary(
+    cfg: &CommandConfig,
+    data: &[u8],
+) -> Result<HashMap<String, SensorValue>, ParseResponseError> {
+    let mut sensors_data = HashMap::new();
+    for sensor in cfg.sensors.iter().filter_map(|s| s.as_ref()) {
+        let field = sensor.binary_field.as_ref()
+            .context(MissingBinaryFieldSnafu { sensor: sensor.name.clone() })?;
+        let value = decode_binary_field(sensor, field, data)?;
+        sensors_data.insert(sensor.name.clone(), value);
+    }
+    Ok(sensors_data)
+}
+
+fn parse_value(
+    sensor: &SensorConfig,
+    value: &str,
+    decimal_comma: bool,
+) -> Result<SensorValue, ParseResponseError> {
+    Ok(match sensor.value_type {
+        ValueType::Integer => SensorValue::Integer(
+            i64::from_str_radix(value, sensor.radix.unwrap_or(10))
+                .context(ExpectedIntegerSnafu { sensor: sensor.name.clone() })?
+        ),
+        ValueType::Float => {
+            let value = if decimal_comma {
+                Cow::Owned(value.replace(',', "."))
+            } else {
+                Cow::Borrowed(value)
+            };
+            SensorValue::Float(
+                value.parse::<f64>()
+                    .context(ExpectedFloatSnafu { sensor: sensor.name.clone() })?
+            )
+        }
+        ValueType::String => SensorValue::String(
+            format_string(sensor, value)
+        ),
+        ValueType::Boolean => SensorValue::Boolean(parse_bool(sensor, value)?),
+        ValueType::Enum => match sensor.map.get(value) {
+            Some(name) => SensorValue::String(name.clone()),
+            None => {
+                log::warn!("Unknown value of '{}' enum sensor: '{value}'", sensor.name);
+                SensorValue::String(value.to_string())
+            }
+        },
+        // Clocks of inverters are often unset so the rest of the response is still published
+        ValueType::DateTime => match parse_datetime(sensor, value) {
+            Some(datetime) => SensorValue::String(datetime),
+            None => {
+                log::warn!("Invalid date and time of '{}' sensor: '{value}'", sensor.name);
+                SensorValue::Unavailable
+            }
+        },
+    })
+}
+
+// Formats the date and time as RFC 3339
+fn parse_datetime(sensor: &SensorConfig, value: &str) -> Option<String> {
+    let format = sensor.datetime_format.as_deref()?;
+    let datetime = match DateTime::parse_from_str(value, format) {
+        Ok(datetime) => datetime,
+        Err(_) => {
+            let offset = match &sensor.utc_offset {
+                Some(offset) => offset.parse::<FixedOffset>().ok()?,
+                None => FixedOffset::east_opt(0)?,
+            };
+            NaiveDateTime::parse_from_str(value, format).ok()?
+                .and_local_timezone(offset)
+                .single()?
+        }
+    };
+    Some(datetime.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn parse_bool(sensor: &SensorConfig, value: &str) -> Result<bool, ParseResponseError> {
+    if value == sensor.true_value.as_deref().unwrap_or("1") {
+        return Ok(true);
+    }
+    if value == sensor.false_value.as_deref().unwrap_or("0") {
+        return Ok(false);
+    }
+    ExpectedBooleanSnafu { sensor: sensor.name.clone(), value }.fail()
+}
+
+// Integers stay integers when both factors are whole numbers and the result fits
+pub fn scale_value(sensor: &SensorConfig, value: SensorValue) -> SensorValue {
+    if sensor.scale.is_none() && sensor.offset.is_none() {
+        return value;
+    }
+    let scale = sensor.scale.unwrap_or(1.0);
+    let offset = sensor.offset.unwrap_or(0.0);
+    match value {
+        SensorValue::Integer(v) => {
+            let scaled = (scale.fract() == 0.0 && offset.fract() == 0.0)
+                .then(|| v.checked_mul(scale as i64)?.checked_add(offset as i64))
+                .flatten();
+            match scaled {
+                Some(scaled) => SensorValue::Integer(scaled),
+                None => SensorValue::Float(v as f64 * scale + offset),
+            }
+        }
+        SensorValue::Float(v) => SensorValue::Float(v * scale + offset),
+        value => value,
+    }
+}
+
+fn format_string(sensor: &SensorConfig, value: &str) -> String {
+    let value = if sensor.trim { value.trim() } else { value };
+    if sensor.strip_leading_zeros {
+        // Zero itself should stay zero
+        let stripped = value.trim_start_matches('0');
+        if stripped.is_empty() && !value.is_empty() {
+            return "0".to_string();
+        }
+        return stripped.to_string();
+    }
+    value.to_string()
+}
+
+fn decode_binary_field(
+    sensor: &SensorConfig,
+    field: &BinaryField,
+    data: &[u8],
+) -> Result<SensorValue, ParseResponseError> {
+    let end = field.offset + field.width;
+    let bytes = data.get(field.offset..end)
+        .context(FieldOutOfRangeSnafu {
+            sensor: sensor.name.clone(),
+            offset: field.offset,
+            end,
+            len: data.len(),
+        })?;
+    let value = match field.decoder {
+        FieldDecoder::Integer => decode_integer(bytes, field.endian, field.signed),
+        FieldDecoder::Bcd => decode_bcd(bytes)
+            .context(InvalidBcdSnafu { sensor: sensor.name.clone(), data: bytes.to_vec() })?,
+    };
+    Ok(match sensor.value_type {
+        ValueType::Integer => SensorValue::Integer(value),
+        ValueType::Float => SensorValue::Float(value as f64),
+        ValueType::String => SensorValue::String(value.to_string()),
+        ValueType::Boolean => SensorValue::Boolean(value != 0),
+        ValueType::DateTime => SensorValue::Unavailable,
+        // Codes are mapped by their decimal representation
+        ValueType::Enum => parse_value(sensor, &value.to_string(), false)?,
+    })
+}
+
+// Two's complement is used for signed values, width must not exceed 8 bytes
+pub fn decode_integer(bytes: &[u8], endian: Endian, signed: bool) -> i64 {
+    let fold = |acc: u64, &b: &u8| (acc << 8) | u64::from(b);
+    let value = match endian {
+        Endian::Big => bytes.iter().fold(0, fold),
+        Endian::Little => bytes.iter().rev().fold(0, fold),
+    };
+    let bits = bytes.len() as u32 * 8;
+    if signed && bits > 0 && bits < 64 {
+        // Shifting the sign bit to the top and back extends it
+        ((value << (64 - bits)) as i64) >> (64 - bits)
+    } else {
+        value as i64
+    }
+}
+
+// Every byte packs two decimal digits, high nibble first
+pub fn decode_bcd(bytes: &[u8]) -> Option<i64> {
+    bytes.iter()
+        .flat_map(|b| [b >> 4, b & 0x0f])
+        .try_fold(0i64, |acc, digit| {
+            if digit > 9 {
+                return None;
+            }
+            acc.checked_mul(10)?.checked_add(digit.into())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{CommandConfig, Endian, ParserConfig, SensorConfig};
+    use crate::inverter::SensorValue;
+    use super::{
+        decode_bcd,
+        decode_integer,
+        parse_fixed_width,
+        parse_value,
+        parse_whitespace,
+        scale_value,
+        ParseResponseError,
+    };
+
+    fn string_sensor(options: &str) -> SensorConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: status, value_type: string, device_class: '', unit_of_measurement: '', \
+              icon: '', {options}}}"
+        )).unwrap()
+    }
+
+    #[test]
+    fn test_parse_integer_radix() {
+        let sensor: SensorConfig = serde_yaml::from_str(
+            "{name: flags, value_type: integer, device_class: '', unit_of_measurement: '', \
+              icon: '', radix: 16}"
+        ).unwrap();
+        assert_eq!(parse_value(&sensor, "1A", false).unwrap(), SensorValue::Integer(26));
+        assert!(matches!(
+            parse_value(&sensor, "0x1A", false),
+            Err(ParseResponseError::ExpectedInteger { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_enum() {
+        let sensor: SensorConfig = serde_yaml::from_str(
+            "{name: charger_status, value_type: enum, device_class: enum, \
+              unit_of_measurement: '', icon: '', map: {0: 'Off', 1: Bulk, 2: Float}}"
+        ).unwrap();
+        assert_eq!(
+            parse_value(&sensor, "1", false).unwrap(),
+            SensorValue::String("Bulk".to_string())
+        );
+        // Unknown codes are passed through
+        assert_eq!(
+            parse_value(&sensor, "7", false).unwrap(),
+            SensorValue::String("7".to_string())
+        );
+    }
+
+    #[test]
+    fn test_scale_value() {
+        let sensor = |options: &str| -> SensorConfig {
+            serde_yaml::from_str(&format!(
+                "{{name: temperature, value_type: integer, device_class: '', \
+                  unit_of_measurement: '', icon: '', {options}}}"
+            )).unwrap()
+        };
+        assert_eq!(
+            scale_value(&sensor("scale: 0.1"), SensorValue::Integer(235)),
+            SensorValue::Float(23.5)
+        );
+        assert_eq!(
+            scale_value(&sensor("scale: 2, offset: -10"), SensorValue::Integer(30)),
+            SensorValue::Integer(50)
+        );
+        assert_eq!(
+            scale_value(&sensor("offset: 0.5"), SensorValue::Float(1.0)),
+            SensorValue::Float(1.5)
+        );
+        assert_eq!(
+            scale_value(&sensor(""), SensorValue::Integer(30)),
+            SensorValue::Integer(30)
+        );
+    }
+
+    fn bool_sensor(options: &str) -> SensorConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: charging, value_type: boolean, device_class: '', unit_of_measurement: '', \
+              icon: '', {options}}}"
+        )).unwrap()
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        let sensor = bool_sensor("");
+        assert_eq!(parse_value(&sensor, "1", false).unwrap(), SensorValue::Boolean(true));
+        assert_eq!(parse_value(&sensor, "0", false).unwrap(), SensorValue::Boolean(false));
+        assert_eq!(
+            parse_value(&sensor, "2", false).unwrap_err(),
+            ParseResponseError::ExpectedBoolean {
+                sensor: "charging".to_string(),
+                value: "2".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_bool_tokens() {
+        let sensor = bool_sensor("true_value: 'Y', false_value: 'N'");
+        assert_eq!(parse_value(&sensor, "Y", false).unwrap(), SensorValue::Boolean(true));
+        assert_eq!(parse_value(&sensor, "N", false).unwrap(), SensorValue::Boolean(false));
+        assert!(parse_value(&sensor, "1", false).is_err());
+    }
+
+    fn datetime_sensor(options: &str) -> SensorConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: clock, value_type: datetime, device_class: timestamp, \
+              unit_of_measurement: '', icon: '', datetime_format: '%Y%m%d%H%M%S', {options}}}"
+        )).unwrap()
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(
+            parse_value(&datetime_sensor(""), "20240131235959", false).unwrap(),
+            SensorValue::String("2024-01-31T23:59:59Z".to_string())
+        );
+        assert_eq!(
+            parse_value(&datetime_sensor("utc_offset: '+02:00'"), "20240131235959", false).unwrap(),
+            SensorValue::String("2024-01-31T23:59:59+02:00".to_string())
+        );
+        assert_eq!(
+            parse_value(&datetime_sensor(""), "20241331235959", false).unwrap(),
+            SensorValue::Unavailable
+        );
+    }
+
+    #[test]
+    fn test_parse_string_preserved() {
+        assert_eq!(
+            parse_value(&string_sensor(""), " 01001 ", false).unwrap(),
+            SensorValue::String(" 01001 ".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_string_trim() {
+        assert_eq!(
+            parse_value(&string_sensor("trim: true"), " 01001 ", false).unwrap(),
+            SensorValue::String("01001".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_string_strip_leading_zeros() {
+        let sensor = string_sensor("strip_leading_zeros: true");
+        assert_eq!(
+            parse_value(&sensor, "01001", false).unwrap(),
+            SensorValue::String("1001".to_string())
+        );
+        assert_eq!(
+            parse_value(&sensor, "000", false).unwrap(),
+            SensorValue::String("0".to_string())
+        );
+        let sensor = string_sensor("trim: true, strip_leading_zeros: true");
+        assert_eq!(
+            parse_value(&sensor, " 01001 ", false).unwrap(),
+            SensorValue::String("1001".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_short_response() {
+        let mut cfg: CommandConfig = serde_yaml::from_str(r#"
+command: "QPIGS"
+sensors:
+- {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+- {name: frequency, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+"#).unwrap();
+        let values = parse_whitespace(&cfg, "233.7", false).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["voltage"], SensorValue::Float(233.7));
+
+        cfg = serde_yaml::from_str(r#"
+command: "QPIGS"
+on_short_response: error
+sensors:
+- {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+- {name: frequency, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+"#).unwrap();
+        assert_eq!(
+            parse_whitespace(&cfg, "233.7", false).unwrap_err(),
+            ParseResponseError::FieldCountMismatch { expected: 2, actual: 1 }
+        );
+        assert_eq!(parse_whitespace(&cfg, "233.7 50.0", false).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_fixed_width() {
+        let cfg: CommandConfig = serde_yaml::from_str(r#"
+command: "QPIGS"
+on_short_response: error
+parser:
+  type: fixed_width
+  widths: [5, 5, 1]
+sensors:
+- {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+- {name: frequency, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+- {name: mode, value_type: string, device_class: "", unit_of_measurement: "", icon: ""}
+"#).unwrap();
+        let ParserConfig::FixedWidth(fixed_width) = &cfg.parser else {
+            panic!("Expected fixed width parser");
+        };
+        let values = parse_fixed_width(&cfg, fixed_width, "230.5 50.0L", false).unwrap();
+        assert_eq!(values["voltage"], SensorValue::Float(230.5));
+        assert_eq!(values["frequency"], SensorValue::Float(50.0));
+        assert_eq!(values["mode"], SensorValue::String("L".to_string()));
+        let values = parse_fixed_width(&cfg, fixed_width, "230.5 50.0L 1", false).unwrap();
+        assert_eq!(values["mode"], SensorValue::String("L".to_string()));
+        assert_eq!(
+            parse_fixed_width(&cfg, fixed_width, "230.5 50.0", false).unwrap_err(),
+            ParseResponseError::FieldWidthOverflow { field: 2, needed: 1, available: 0 }
+        );
+        assert_eq!(
+            parse_fixed_width(&cfg, fixed_width, "230.5 50", false).unwrap_err(),
+            ParseResponseError::FieldWidthOverflow { field: 1, needed: 5, available: 3 }
+        );
+    }
+
+    #[test]
+    fn test_decode_integer() {
+        assert_eq!(decode_integer(&[0x01, 0x2c], Endian::Big, false), 300);
+        assert_eq!(decode_integer(&[0x2c, 0x01], Endian::Little, false), 300);
+        assert_eq!(decode_integer(&[0xff], Endian::Big, false), 255);
+        assert_eq!(decode_integer(&[0xff], Endian::Big, true), -1);
+        assert_eq!(decode_integer(&[0x9c, 0xff, 0xff, 0xff], Endian::Little, true), -100);
+        assert_eq!(decode_integer(&[0x7f, 0xff], Endian::Big, true), 32767);
+    }
+
+    #[test]
+    fn test_decode_bcd() {
+        assert_eq!(decode_bcd(&[0x12, 0x34]), Some(1234));
+        assert_eq!(decode_bcd(&[0x00, 0x07]), Some(7));
+        assert_eq!(decode_bcd(&[]), Some(0));
+        assert_eq!(decode_bcd(&[0x1a]), None);
+    }
+
+    #[test]
+    fn test_parse_capture_extra() {
+        let mut cfg: CommandConfig = serde_yaml::from_str(r#"
+command: "QPIGS"
+capture_extra: qpigs_extra
+sensors:
+- {name: voltage, value_type: float, device_class: "", unit_of_measurement: "", icon: ""}
+- null
+"#).unwrap();
+        let values = parse_whitespace(&cfg, "233.7 50.0 0001 110", false).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["voltage"], SensorValue::Float(233.7));
+        assert_eq!(values["qpigs_extra"], SensorValue::String("0001 110".to_string()));
+        let values = parse_whitespace(&cfg, "233.7 50.0", false).unwrap();
+        assert_eq!(values["qpigs_extra"], SensorValue::String(String::new()));
+
+        cfg.capture_extra = None;
+        assert_eq!(parse_whitespace(&cfg, "233.7 50.0 0001 110", false).unwrap().len(), 1);
+    }
+}
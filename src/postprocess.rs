@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use snafu::{Whatever, whatever, ResultExt};
+
+use crate::inverter::SensorValue;
+use crate::{BINARY_SENSOR_OFF, BINARY_SENSOR_ON, UNAVAILABLE_STATE};
+
+const POLL_INTERVAL_MS: u64 = 10;
+
+// Pipes sensors data as a JSON object through a shell command
+// and reads transformed JSON object from its stdout, the command is killed after the timeout
+pub fn post_process(
+    cmd: &str,
+    sensors_data: &HashMap<String, SensorValue>,
+    timeout: Duration,
+) -> Result<HashMap<String, String>, Whatever> {
+    let input = serde_json::to_vec(sensors_data)
+        .with_whatever_context(|e| format!("Error when serializing sensors data: {e}"))?;
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        // Own process group, so processes forked by the shell are killed with it
+        .process_group(0)
+        .spawn()
+        .with_whatever_context(|e| format!("Cannot spawn post-process command: {e}"))?;
+    let deadline = Instant::now() + timeout;
+    // Both pipes are served by threads, a command writing before it has read all of its input
+    // would block otherwise
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let (input_sender, input_receiver) = mpsc::channel();
+    thread::spawn(move || input_sender.send(stdin.write_all(&input)));
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (output_sender, output_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = vec!();
+        output_sender.send(stdout.read_to_end(&mut output).map(|_| output))
+    });
+
+    let output = match output_receiver.recv_timeout(timeout) {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            kill(&mut child);
+            whatever!("Cannot read from post-process command: {e}");
+        }
+        Err(_) => {
+            kill(&mut child);
+            whatever!("Post-process command timed out after {timeout:?}");
+        }
+    };
+    let input_timeout = deadline.saturating_duration_since(Instant::now());
+    if let Ok(Err(e)) = input_receiver.recv_timeout(input_timeout) {
+        kill(&mut child);
+        whatever!("Cannot write to post-process command: {e}");
+    }
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+            Ok(None) => {
+                kill(&mut child);
+                whatever!("Post-process command timed out after {timeout:?}");
+            }
+            Err(e) => whatever!("Error waiting for post-process command: {e}"),
+        }
+    };
+    if !status.success() {
+        whatever!("Post-process command exited with {status}");
+    }
+
+    let values: HashMap<String, serde_json::Value> = serde_json::from_slice(&output)
+        .with_whatever_context(|e| format!("Invalid post-process command output: {e}"))?;
+    Ok(
+        values.into_iter()
+            .map(|(name, value)| {
+                // Same states as the values published without post-processing
+                let value = match value {
+                    serde_json::Value::String(v) => v,
+                    serde_json::Value::Bool(v) => {
+                        if v { BINARY_SENSOR_ON } else { BINARY_SENSOR_OFF }.to_string()
+                    }
+                    serde_json::Value::Null => UNAVAILABLE_STATE.to_string(),
+                    v => v.to_string(),
+                };
+                (name, value)
+            })
+            .collect()
+    )
+}
+
+// Kills the whole process group and reaps the child, so no zombie is left behind
+fn kill(child: &mut Child) {
+    let pgid = child.id() as libc::pid_t;
+    let res = if unsafe { libc::kill(-pgid, libc::SIGKILL) } == 0 {
+        child.wait().map(|_| ())
+    } else {
+        Err(io::Error::last_os_error())
+    };
+    if let Err(e) = res {
+        log::warn!("Cannot kill post-process command: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::inverter::SensorValue;
+    use super::post_process;
+
+    #[test]
+    fn test_post_process() {
+        let mut sensors_data = HashMap::new();
+        sensors_data.insert("grid_voltage".to_string(), SensorValue::Float(233.7));
+        sensors_data.insert("grid_connected".to_string(), SensorValue::Boolean(true));
+        sensors_data.insert("charging".to_string(), SensorValue::Boolean(false));
+        sensors_data.insert("pv_power".to_string(), SensorValue::Unavailable);
+        let values = post_process(
+            "sed 's/233.7/234/; s/grid_voltage/out_voltage/'",
+            &sensors_data,
+            Duration::from_secs(5),
+        ).unwrap();
+        let mut expected_values = HashMap::new();
+        expected_values.insert("out_voltage".to_string(), "234".to_string());
+        expected_values.insert("grid_connected".to_string(), "ON".to_string());
+        expected_values.insert("charging".to_string(), "OFF".to_string());
+        expected_values.insert("pv_power".to_string(), "None".to_string());
+        assert_eq!(values, expected_values);
+    }
+
+    #[test]
+    fn test_post_process_failed() {
+        assert!(post_process("exit 1", &HashMap::new(), Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_post_process_timeout() {
+        let started_at = Instant::now();
+        // The shell forks sleep instead of replacing itself with it
+        let err = post_process("sleep 10; true", &HashMap::new(), Duration::from_millis(100))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Post-process command timed out"), "{err}");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_post_process_timeout_kills_children() {
+        let pid_path = std::env::temp_dir()
+            .join(format!("inverter2mqtt-test-{}.pid", std::process::id()));
+        let cmd = format!("sleep 10 & echo $! > {}; wait", pid_path.display());
+        post_process(&cmd, &HashMap::new(), Duration::from_millis(100)).unwrap_err();
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        std::fs::remove_file(&pid_path).unwrap();
+        // Killed process is gone or is a zombie waiting to be reaped by init
+        let is_killed = || {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+                .map_or(true, |stat| stat.contains(") Z "))
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_killed() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(is_killed(), "sleep is still running");
+    }
+
+    #[test]
+    fn test_post_process_large_input() {
+        // More than a pipe buffer, echoed back while the input is still being written
+        let sensors_data = (0..10000)
+            .map(|i| (format!("sensor_{i}"), SensorValue::Integer(i)))
+            .collect::<HashMap<_, _>>();
+        let values = post_process("cat", &sensors_data, Duration::from_secs(5)).unwrap();
+        assert_eq!(values.len(), 10000);
+        assert_eq!(values["sensor_42"], "42");
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::RawConfig;
+use crate::inverter::{DeviceError, InverterDevice};
+
+const POLL_INTERVAL_MS: u64 = 10;
+
+// Reads and writes any character device, FIFO or socket file
+pub struct RawInverterDevice {
+    file: File,
+    timeout: Duration,
+}
+
+impl RawInverterDevice {
+    pub fn open(cfg: &RawConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&cfg.path)?;
+        Ok(Self {
+            file,
+            timeout: Duration::from_millis(cfg.timeout_ms.into()),
+        })
+    }
+
+    fn wait<T>(
+        &mut self,
+        mut op: impl FnMut(&mut File) -> std::io::Result<T>,
+    ) -> Result<T, DeviceError> {
+        let started_at = Instant::now();
+        loop {
+            match op(&mut self.file) {
+                Ok(res) => return Ok(res),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if started_at.elapsed() >= self.timeout {
+                        return Err(DeviceError::Io { source: ErrorKind::TimedOut.into() });
+                    }
+                    sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                }
+                Err(e) => return Err(DeviceError::Io { source: e }),
+            }
+        }
+    }
+}
+
+impl InverterDevice for RawInverterDevice {
+    fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
+        let mut written = 0;
+        while written < buf.len() {
+            let n = self.wait(|file| file.write(&buf[written..]))?;
+            // Nothing would be written on the next attempts either
+            if n == 0 {
+                return Err(DeviceError::Io { source: ErrorKind::WriteZero.into() });
+            }
+            written += n;
+        }
+        Ok(written)
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        // End of file means there is no writer yet so wait for data as well
+        self.wait(|file| match file.read(buf)? {
+            0 => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        })
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use snafu::Whatever;
+
+use crate::inverter::{DeviceError, InverterDevice};
+
+const REOPEN_MIN_DELAY_SECS: u64 = 10;
+const REOPEN_MAX_DELAY_SECS: u64 = 300;
+// Consecutive USB errors after which the device is considered gone
+const REOPEN_AFTER_ERRORS: u32 = 3;
+
+// Drops the handle of an unplugged device and finds the device again,
+// so a replugged inverter recovers without restarting the process
+pub struct ReopeningUsbDevice<T, F> {
+    // Finds the device again, None when it is not plugged in
+    open: F,
+    dev: Option<T>,
+    packet_size: usize,
+    consecutive_errors: u32,
+    reopen_delay: Duration,
+    reopen_at: Instant,
+}
+
+impl<T, F> ReopeningUsbDevice<T, F>
+where
+    T: InverterDevice,
+    F: FnMut() -> Result<Option<T>, Whatever>,
+{
+    pub fn new(dev: T, open: F) -> Self {
+        Self {
+            open,
+            packet_size: dev.packet_size(),
+            dev: Some(dev),
+            consecutive_errors: 0,
+            reopen_delay: Duration::from_secs(REOPEN_MIN_DELAY_SECS),
+            reopen_at: Instant::now(),
+        }
+    }
+
+    // Rediscovery is retried with an exponential backoff while the device is missing
+    fn device(&mut self) -> Result<&mut T, DeviceError> {
+        if self.dev.is_none() {
+            if Instant::now() < self.reopen_at {
+                return Err(DeviceError::Usb { source: rusb::Error::NoDevice });
+            }
+            match (self.open)() {
+                Ok(Some(dev)) => {
+                    log::info!("USB device is reopened");
+                    self.reopen_delay = Duration::from_secs(REOPEN_MIN_DELAY_SECS);
+                    self.dev = Some(dev);
+                }
+                res => {
+                    if let Err(e) = res {
+                        log::warn!("{e}");
+                    }
+                    log::warn!("USB device is not found, next attempt in {:?}", self.reopen_delay);
+                    self.reopen_at = Instant::now() + self.reopen_delay;
+                    self.reopen_delay = (self.reopen_delay * 2)
+                        .min(Duration::from_secs(REOPEN_MAX_DELAY_SECS));
+                    return Err(DeviceError::Usb { source: rusb::Error::NoDevice });
+                }
+            }
+        }
+        Ok(self.dev.as_mut().expect("device is opened above"))
+    }
+
+    fn usb<R>(
+        &mut self,
+        op: impl FnOnce(&mut T) -> Result<R, DeviceError>,
+    ) -> Result<R, DeviceError> {
+        let res = self.device().and_then(op);
+        match &res {
+            Ok(_) => self.consecutive_errors = 0,
+            Err(DeviceError::Usb { source }) if self.dev.is_some() => {
+                self.consecutive_errors += 1;
+                if *source == rusb::Error::NoDevice
+                    || self.consecutive_errors >= REOPEN_AFTER_ERRORS
+                {
+                    log::warn!("Releasing USB device after error: {source}");
+                    self.dev = None;
+                    self.consecutive_errors = 0;
+                }
+            }
+            Err(_) => {}
+        }
+        res
+    }
+}
+
+impl<T, F> InverterDevice for ReopeningUsbDevice<T, F>
+where
+    T: InverterDevice,
+    F: FnMut() -> Result<Option<T>, Whatever>,
+{
+    fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
+        self.usb(|dev| dev.send_request(buf))
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.usb(|dev| dev.read_response(buf))
+    }
+
+    // Packet size of a replugged device is the same
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    fn release(&mut self) {
+        if self.dev.take().is_some() {
+            log::debug!("USB device is released");
+        }
+        self.reopen_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::inverter::{DeviceError, Inverter, InverterError};
+    use crate::testutil::MockDevice;
+    use super::ReopeningUsbDevice;
+
+    const QID_RESPONSE: [u8; 8] = [b'(', b'0', 0xb9, 0x1c, 13, 0, 0, 0];
+
+    #[test]
+    fn test_reopen_after_disconnect() {
+        let opened = Cell::new(0);
+        let dev = ReopeningUsbDevice::new(
+            MockDevice::new().fail_send(DeviceError::Usb { source: rusb::Error::NoDevice }),
+            || {
+                opened.set(opened.get() + 1);
+                Ok(Some(MockDevice::new().respond(&QID_RESPONSE)))
+            },
+        );
+        let mut inverter = Inverter::builder(dev).build();
+        assert_eq!(
+            inverter.query("QID").unwrap_err(),
+            InverterError::Device { source: DeviceError::Usb { source: rusb::Error::NoDevice } }
+        );
+        assert_eq!(opened.get(), 0);
+        // Replugged device is found again by the next query
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+        assert_eq!(opened.get(), 1);
+    }
+
+    #[test]
+    fn test_reopen_backs_off_while_missing() {
+        let opened = Cell::new(0);
+        let dev = ReopeningUsbDevice::new(
+            MockDevice::new().fail_send(DeviceError::Usb { source: rusb::Error::NoDevice }),
+            || {
+                opened.set(opened.get() + 1);
+                Ok(None::<MockDevice>)
+            },
+        );
+        let mut inverter = Inverter::builder(dev).build();
+        for _ in 0..3 {
+            inverter.query("QID").unwrap_err();
+        }
+        // Searched once, the next search waits for the delay
+        assert_eq!(opened.get(), 1);
+    }
+
+    #[test]
+    fn test_retry_after_timeout() {
+        let opened = Cell::new(0);
+        let dev = ReopeningUsbDevice::new(
+            MockDevice::new()
+                .timeout_read()
+                .respond(&QID_RESPONSE)
+                .pass_send()
+                .pass_send()
+                .fail_send(DeviceError::Usb { source: rusb::Error::Pipe })
+                .fail_send(DeviceError::Usb { source: rusb::Error::Pipe })
+                .fail_send(DeviceError::Usb { source: rusb::Error::Pipe }),
+            || {
+                opened.set(opened.get() + 1);
+                Ok(Some(MockDevice::new().respond(&QID_RESPONSE)))
+            },
+        );
+        let mut inverter = Inverter::builder(dev).build();
+        assert_eq!(
+            inverter.query("QID").unwrap_err(),
+            InverterError::Device { source: DeviceError::Usb { source: rusb::Error::Timeout } }
+        );
+        // A single timeout keeps the device
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+        assert_eq!(opened.get(), 0);
+        // Repeated errors release it, the next query finds it again
+        for _ in 0..3 {
+            inverter.query("QID").unwrap_err();
+        }
+        assert_eq!(inverter.query("QID").unwrap(), "0");
+        assert_eq!(opened.get(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+use snafu::{Whatever, whatever};
+
+// Counts consecutive retries of a failing operation, unlimited without a maximum
+pub struct RetryLimit {
+    max_retries: Option<u32>,
+    retries: u32,
+}
+
+impl RetryLimit {
+    pub fn new(max_retries: Option<u32>) -> Self {
+        Self {
+            max_retries,
+            retries: 0,
+        }
+    }
+
+    // Called after a failure before retrying, fails when the retries are exhausted
+    pub fn retry(&mut self, what: &str) -> Result<(), Whatever> {
+        if self.max_retries.is_some_and(|max| self.retries >= max) {
+            whatever!("Giving up {what} after {} retries", self.retries);
+        }
+        self.retries += 1;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryLimit;
+
+    #[test]
+    fn test_retry_limit() {
+        let mut limit = RetryLimit::new(Some(2));
+        assert!(limit.retry("connecting").is_ok());
+        assert!(limit.retry("connecting").is_ok());
+        assert_eq!(
+            limit.retry("connecting").unwrap_err().to_string(),
+            "Giving up connecting after 2 retries"
+        );
+        limit.reset();
+        assert!(limit.retry("connecting").is_ok());
+
+        let mut limit = RetryLimit::new(Some(0));
+        assert!(limit.retry("connecting").is_err());
+    }
+
+    #[test]
+    fn test_retry_unlimited() {
+        let mut limit = RetryLimit::new(None);
+        for _ in 0..1000 {
+            assert!(limit.retry("connecting").is_ok());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::config::SerialConfig;
+use crate::inverter::{DeviceError, InverterDevice, END_RESPONSE_MARKER};
+
+// RS-232 or USB-CDC port configured for the inverter line settings
+pub struct SerialInverterDevice {
+    port: Box<dyn SerialPort>,
+    // Configured baud rate followed by the candidates
+    baud_rates: Vec<u32>,
+    current_baud_rate: usize,
+}
+
+impl SerialInverterDevice {
+    pub fn open(cfg: &SerialConfig) -> serialport::Result<Self> {
+        let mut port = serialport::new(cfg.path.to_string_lossy(), cfg.baud_rate)
+            .timeout(Duration::from_millis(cfg.timeout_ms.into()))
+            .open()?;
+        if let Some(dtr) = cfg.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = cfg.rts {
+            port.write_request_to_send(rts)?;
+        }
+        Ok(Self {
+            port,
+            baud_rates: baud_rates(cfg),
+            current_baud_rate: 0,
+        })
+    }
+}
+
+fn baud_rates(cfg: &SerialConfig) -> Vec<u32> {
+    let mut baud_rates = vec![cfg.baud_rate];
+    for &baud_rate in cfg.candidate_baud_rates.iter() {
+        if !baud_rates.contains(&baud_rate) {
+            baud_rates.push(baud_rate);
+        }
+    }
+    baud_rates
+}
+
+impl InverterDevice for SerialInverterDevice {
+    fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
+        self.port.write_all(buf)
+            .and_then(|_| self.port.flush())
+            .map_err(|e| DeviceError::Io { source: e })?;
+        Ok(buf.len())
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        // Bytes trickle in at a low baud rate so collect them up to the terminator
+        let mut read = 0;
+        while read < buf.len() && !buf[..read].contains(&END_RESPONSE_MARKER) {
+            match self.port.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                // Frame reader decides whether the received part is enough
+                Err(e) if e.kind() == ErrorKind::TimedOut && read > 0 => break,
+                Err(e) => return Err(DeviceError::Io { source: e }),
+            }
+        }
+        Ok(read)
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.baud_rates[self.current_baud_rate])
+    }
+
+    fn next_baud_rate(&mut self) -> Option<u32> {
+        if self.baud_rates.len() < 2 {
+            return None;
+        }
+        self.current_baud_rate = (self.current_baud_rate + 1) % self.baud_rates.len();
+        let baud_rate = self.baud_rates[self.current_baud_rate];
+        match self.port.set_baud_rate(baud_rate) {
+            Ok(()) => Some(baud_rate),
+            Err(e) => {
+                log::warn!("Cannot switch serial port to {baud_rate} baud: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::SerialConfig;
+    use super::baud_rates;
+
+    #[test]
+    fn test_baud_rates() {
+        let cfg: SerialConfig = serde_yaml::from_str(r#"
+path: "/dev/ttyUSB0"
+timeout_ms: 1000
+candidate_baud_rates: [9600, 2400, 19200]
+"#).unwrap();
+        assert_eq!(baud_rates(&cfg), vec![2400, 9600, 19200]);
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use snafu::{Whatever, ResultExt};
+
+// Writes the latest sensor values as a JSON object. Readers never see a partially written file
+// because it is replaced by renaming a temporary file in the same directory
+pub fn write_snapshot(path: &Path, values: &HashMap<String, String>) -> Result<(), Whatever> {
+    let sorted = values.iter().collect::<BTreeMap<_, _>>();
+    let json = serde_json::to_vec_pretty(&sorted)
+        .with_whatever_context(|e| format!("Error when serializing snapshot: {e}"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, json)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .with_whatever_context(|e| format!("Cannot write snapshot to {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::write_snapshot;
+
+    #[test]
+    fn test_write_snapshot() {
+        let dir = std::env::temp_dir()
+            .join(format!("inverter2mqtt-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        let mut values = HashMap::new();
+        values.insert("grid_voltage".to_string(), "230.1".to_string());
+        write_snapshot(&path, &values).unwrap();
+        values.insert("battery_voltage".to_string(), "52.4".to_string());
+        write_snapshot(&path, &values).unwrap();
+        let written: HashMap<String, String> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, values);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::config::TcpConfig;
+use crate::inverter::{DeviceError, InverterDevice};
+
+// Serial port exposed by a serial-to-Ethernet adapter
+pub struct TcpInverterDevice {
+    address: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl TcpInverterDevice {
+    // Connection is established by the first request
+    pub fn new(cfg: &TcpConfig) -> Self {
+        Self {
+            address: cfg.address.clone(),
+            timeout: Duration::from_millis(cfg.timeout_ms.into()),
+            stream: None,
+        }
+    }
+
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    log::info!("Connected to {addr}");
+                    return Ok(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| ErrorKind::AddrNotAvailable.into()))
+    }
+
+    fn stream(&mut self) -> std::io::Result<&mut TcpStream> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        Ok(self.stream.insert(stream))
+    }
+
+    // Dropped connection is re-established by the next request
+    fn io<T>(
+        &mut self,
+        op: impl FnOnce(&mut TcpStream) -> std::io::Result<T>,
+    ) -> Result<T, DeviceError> {
+        let res = self.stream().and_then(op);
+        if let Err(e) = &res {
+            if !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                self.stream = None;
+            }
+        }
+        res.map_err(|e| DeviceError::Io { source: e })
+    }
+}
+
+impl InverterDevice for TcpInverterDevice {
+    fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
+        self.io(|stream| stream.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.io(|stream| match stream.read(buf)? {
+            0 => Err(ErrorKind::UnexpectedEof.into()),
+            n => Ok(n),
+        })
+    }
+
+    fn release(&mut self) {
+        self.stream = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::config::TcpConfig;
+    use crate::inverter::InverterDevice;
+    use super::TcpInverterDevice;
+
+    #[test]
+    fn test_tcp_device_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = TcpConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            timeout_ms: 1000,
+        };
+        let server = thread::spawn(move || {
+            for resp in [b"(1\r", b"(2\r"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut req = [0; 4];
+                stream.read_exact(&mut req).unwrap();
+                stream.write_all(resp).unwrap();
+                // Closes the connection
+            }
+        });
+        let mut dev = TcpInverterDevice::new(&cfg);
+        let mut buf = [0; 8];
+        assert_eq!(dev.send_request(b"QID\r").unwrap(), 4);
+        let n = dev.read_response(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"(1\r");
+        assert!(dev.read_response(&mut buf).is_err());
+        assert_eq!(dev.send_request(b"QID\r").unwrap(), 4);
+        let n = dev.read_response(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"(2\r");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_device_release() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = TcpConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            timeout_ms: 1000,
+        };
+        let server = thread::spawn(move || {
+            for resp in [b"(1\r", b"(2\r"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut req = [0; 4];
+                stream.read_exact(&mut req).unwrap();
+                stream.write_all(resp).unwrap();
+                // Released by the client
+                assert_eq!(stream.read(&mut req).unwrap(), 0);
+            }
+        });
+        let mut dev = TcpInverterDevice::new(&cfg);
+        let mut buf = [0; 8];
+        for expected in [b"(1\r", b"(2\r"] {
+            assert_eq!(dev.send_request(b"QID\r").unwrap(), 4);
+            let n = dev.read_response(&mut buf).unwrap();
+            assert_eq!(&buf[..n], expected);
+            dev.release();
+        }
+        server.join().unwrap();
+    }
+}
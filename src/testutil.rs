@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+
+use rusb::Error as UsbError;
+
+use crate::inverter::{DeviceError, InverterDevice, REPORT_SIZE};
+
+const STALE_BYTE: u8 = 0xff;
+
+// In-memory device driven by a script of expected requests and response bytes
+#[derive(Default)]
+pub struct MockDevice {
+    expected_requests: VecDeque<Vec<u8>>,
+    response: VecDeque<u8>,
+    endless_byte: Option<u8>,
+    packet_size: Option<usize>,
+    read_limit: Option<usize>,
+    // Outcomes of the next calls, None lets a call through to the script
+    send_errors: VecDeque<Option<DeviceError>>,
+    read_errors: VecDeque<Option<DeviceError>>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Asserts that the next request is equal to the given bytes
+    pub fn expect_request(mut self, request: &[u8]) -> Self {
+        self.expected_requests.push_back(request.to_vec());
+        self
+    }
+
+    // Appends bytes to the stream returned by reads
+    pub fn respond(mut self, response: &[u8]) -> Self {
+        self.response.extend(response);
+        self
+    }
+
+    // Fills reads with the byte after the response stream is exhausted
+    pub fn respond_endless(mut self, byte: u8) -> Self {
+        self.endless_byte = Some(byte);
+        self
+    }
+
+    // Reads into a buffer smaller than the packet fail like libusb does
+    pub fn packet_size(mut self, packet_size: usize) -> Self {
+        self.packet_size = Some(packet_size);
+        self
+    }
+
+    // Returns at most the number of bytes per read like partial transfers do
+    pub fn read_limit(mut self, read_limit: usize) -> Self {
+        self.read_limit = Some(read_limit);
+        self
+    }
+
+    pub fn fail_send(mut self, err: DeviceError) -> Self {
+        self.send_errors.push_back(Some(err));
+        self
+    }
+
+    pub fn fail_read(mut self, err: DeviceError) -> Self {
+        self.read_errors.push_back(Some(err));
+        self
+    }
+
+    pub fn timeout_read(self) -> Self {
+        self.fail_read(DeviceError::Usb { source: UsbError::Timeout })
+    }
+
+    // Lets the next send through, so the failures queued after it hit later calls
+    pub fn pass_send(mut self) -> Self {
+        self.send_errors.push_back(None);
+        self
+    }
+
+    // Lets the next read through, so the failures queued after it hit later calls
+    pub fn pass_read(mut self) -> Self {
+        self.read_errors.push_back(None);
+        self
+    }
+}
+
+impl InverterDevice for MockDevice {
+    fn send_request(&mut self, buf: &[u8]) -> Result<usize, DeviceError> {
+        if let Some(Some(err)) = self.send_errors.pop_front() {
+            return Err(err);
+        }
+        if let Some(expected_request) = self.expected_requests.pop_front() {
+            assert_eq!(buf, expected_request.as_slice());
+        }
+        Ok(buf.len())
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        if let Some(Some(err)) = self.read_errors.pop_front() {
+            return Err(err);
+        }
+        if buf.len() < InverterDevice::packet_size(self) {
+            return Err(DeviceError::Usb { source: UsbError::Overflow });
+        }
+        if self.response.is_empty() {
+            return match self.endless_byte {
+                Some(byte) => {
+                    buf.fill(byte);
+                    Ok(buf.len())
+                }
+                None => Err(DeviceError::Usb { source: UsbError::Timeout }),
+            };
+        }
+        let n = buf.len()
+            .min(self.response.len())
+            .min(self.read_limit.unwrap_or(usize::MAX));
+        for (dst, src) in buf.iter_mut().zip(self.response.drain(..n)) {
+            *dst = src;
+        }
+        // Bytes after the read count are not part of the response
+        buf[n..].fill(STALE_BYTE);
+        Ok(n)
+    }
+
+    fn packet_size(&self) -> usize {
+        self.packet_size.unwrap_or(REPORT_SIZE)
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    last_logged: Instant,
+    suppressed: u64,
+    // Latest suppressed message, logged with the count when the entry expires
+    last_suppressed: Option<String>,
+}
+
+// Limits warnings of the same kind, callers pass a stable key of the kind
+// because messages include changing details like error texts
+pub struct LogThrottle {
+    interval: Duration,
+    entries: HashMap<String, Entry>,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn warn(&mut self, key: &str, msg: &str) {
+        let now = Instant::now();
+        match self.check(key, msg, now) {
+            Some(0) => log::warn!("{msg}"),
+            Some(suppressed) => log::warn!("{msg} (suppressed {suppressed} similar messages)"),
+            None => {}
+        }
+        for (msg, suppressed) in self.evict(now) {
+            log::warn!("{msg} (suppressed {suppressed} similar messages)");
+        }
+    }
+
+    // Returns number of suppressed messages when the message should be logged
+    fn check(&mut self, key: &str, msg: &str, now: Instant) -> Option<u64> {
+        match self.entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.last_logged) < self.interval => {
+                entry.suppressed += 1;
+                entry.last_suppressed = Some(msg.to_string());
+                None
+            }
+            Some(entry) => {
+                let suppressed = entry.suppressed;
+                entry.last_logged = now;
+                entry.suppressed = 0;
+                entry.last_suppressed = None;
+                Some(suppressed)
+            }
+            None => {
+                let entry = Entry { last_logged: now, suppressed: 0, last_suppressed: None };
+                self.entries.insert(key.to_string(), entry);
+                Some(0)
+            }
+        }
+    }
+
+    // Drops entries not logged within the interval, so keys of past errors do not pile up.
+    // Returns the latest suppressed messages of the dropped entries with their counts
+    fn evict(&mut self, now: Instant) -> Vec<(String, u64)> {
+        let interval = self.interval;
+        let mut expired = vec!();
+        self.entries.retain(|_, entry| {
+            if now.duration_since(entry.last_logged) < interval {
+                return true;
+            }
+            if let Some(msg) = entry.last_suppressed.take() {
+                expired.push((msg, entry.suppressed));
+            }
+            false
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::LogThrottle;
+
+    #[test]
+    fn test_log_throttle() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(throttle.check("publish", "error 1", start), Some(0));
+        assert_eq!(throttle.check("publish", "error 2", at(10)), None);
+        assert_eq!(throttle.check("publish", "error 3", at(20)), None);
+        assert_eq!(throttle.check("command", "error 1", at(20)), Some(0));
+        assert_eq!(throttle.check("publish", "error 4", at(60)), Some(2));
+        assert_eq!(throttle.check("publish", "error 5", at(70)), None);
+    }
+
+    #[test]
+    fn test_log_throttle_eviction() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        throttle.check("publish", "error 1", start);
+        throttle.check("publish", "error 2", at(10));
+        throttle.check("command", "error 1", at(30));
+        assert!(throttle.evict(at(59)).is_empty());
+        // Suppressed messages of an evicted entry are not lost
+        assert_eq!(throttle.evict(at(60)), vec!(("error 2".to_string(), 1)));
+        assert_eq!(throttle.entries.len(), 1);
+        // Nothing was suppressed, so the entry is dropped silently
+        assert!(throttle.evict(at(90)).is_empty());
+        assert!(throttle.entries.is_empty());
+        assert_eq!(throttle.check("publish", "error 3", at(90)), Some(0));
+    }
+
+    #[test]
+    fn test_log_throttle_disabled() {
+        let mut throttle = LogThrottle::new(Duration::ZERO);
+        let start = Instant::now();
+        assert_eq!(throttle.check("publish", "error", start), Some(0));
+        assert_eq!(throttle.check("publish", "error", start), Some(0));
+    }
+}